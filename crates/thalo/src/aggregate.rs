@@ -17,7 +17,10 @@ where
 
 #[doc(hidden)]
 pub mod wit_aggregate {
-    wit_bindgen_guest_rust::generate!("../../wit/aggregate.wit");
+    wit_bindgen_guest_rust::generate!({
+        path: "../../wit",
+        world: "aggregate",
+    });
 
     pub use aggregate::*;
     use chrono::{TimeZone, Utc};
@@ -50,14 +53,14 @@ pub mod wit_aggregate {
         fn handle(state: State, ctx: Context, command: Command) -> Result<Vec<Event>, Error> {
             let state: T = serde_json::from_slice(&state)
                 .map_err(|err| Error::DeserializeState(err.to_string()))?;
-            let mut ctx = ctx.try_into()?;
+            let mut ctx: crate::Context = ctx.try_into()?;
             let events = <T::Command as super::Commands>::handle(
                 &state,
                 &mut ctx,
                 &command.command,
                 command.payload,
             )?;
-            events
+            let events = events
                 .into_iter()
                 .map(|event| {
                     Result::<_, Error>::Ok(Event {
@@ -66,7 +69,24 @@ pub mod wit_aggregate {
                         payload: event.payload()?,
                     })
                 })
-                .collect()
+                .collect::<Result<_, _>>()?;
+            // Only sent once the command is handled, as the runtime discards the
+            // changes of failed commands
+            for change in ctx.take_schedule_changes() {
+                match change {
+                    crate::ScheduleChange::Schedule(scheduled) => scheduler::schedule(
+                        &scheduled.id,
+                        &scheduled.aggregate,
+                        &scheduled.entity_id,
+                        &scheduled.command,
+                        &scheduled.payload,
+                        scheduled.due.timestamp_millis(),
+                    ),
+                    crate::ScheduleChange::Cancel(id) => scheduler::cancel(&id),
+                }
+            }
+
+            Ok(events)
         }
     }
 
@@ -88,14 +108,14 @@ pub mod wit_aggregate {
                 return Err(Error::DeserializeContext("invalid timestamp".to_string()));
             };
 
-            Ok(crate::Context {
+            Ok(crate::Context::new(
                 id,
                 stream_name,
-                position: ctx.position,
-                global_position: ctx.global_position,
+                ctx.position,
+                ctx.global_position,
                 metadata,
                 time,
-            })
+            ))
         }
     }
 
@@ -4,9 +4,18 @@ use chrono::{DateTime, Utc};
 use message_db::message::Metadata;
 use message_db::stream_name::StreamName;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use uuid::Uuid;
 
-use crate::{Aggregate, Error};
+use crate::{Aggregate, Error, ScheduleChange, ScheduledCommand};
+
+/// Metadata property correlating all messages resulting from the same
+/// request.
+pub const CORRELATION_ID_PROPERTY: &str = "correlation_id";
+/// Metadata property holding the W3C `traceparent` of a message.
+pub const TRACEPARENT_PROPERTY: &str = "traceparent";
+/// Metadata property holding the W3C `tracestate` of a message.
+pub const TRACESTATE_PROPERTY: &str = "tracestate";
 
 pub trait Commands {
     type Aggregate: Aggregate;
@@ -35,10 +44,102 @@ pub struct Context {
     pub global_position: i64,
     pub metadata: Metadata,
     pub time: DateTime<Utc>,
+    /// Changes to scheduled commands requested while handling a command,
+    /// saved by the runtime along with the resulting events.
+    #[serde(skip)]
+    schedule_changes: Vec<ScheduleChange>,
+}
+
+/// W3C trace context, propagating a trace across messages.
+///
+/// See <https://www.w3.org/TR/trace-context/>.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    pub traceparent: String,
+    pub tracestate: Option<String>,
 }
 
 impl Context {
+    pub fn new(
+        id: Uuid,
+        stream_name: StreamName,
+        position: i64,
+        global_position: i64,
+        metadata: Metadata,
+        time: DateTime<Utc>,
+    ) -> Self {
+        Context {
+            id,
+            stream_name,
+            position,
+            global_position,
+            metadata,
+            time,
+            schedule_changes: Vec::new(),
+        }
+    }
+
     pub fn processed(&self, sequence: i64) -> bool {
         sequence >= self.position
     }
+
+    /// Schedules a command to be submitted once it comes due, replacing any
+    /// pending schedule with the same ID.
+    ///
+    /// The command is only scheduled if the command being handled is
+    /// accepted.
+    pub fn schedule(&mut self, command: ScheduledCommand) {
+        self.schedule_changes
+            .push(ScheduleChange::Schedule(command));
+    }
+
+    /// Cancels a pending schedule of this aggregate instance by its ID.
+    pub fn cancel_schedule(&mut self, id: impl Into<String>) {
+        self.schedule_changes
+            .push(ScheduleChange::Cancel(id.into()));
+    }
+
+    /// Returns the changes to scheduled commands requested so far.
+    pub fn schedule_changes(&self) -> &[ScheduleChange] {
+        &self.schedule_changes
+    }
+
+    /// Takes the changes to scheduled commands requested so far.
+    pub(crate) fn take_schedule_changes(&mut self) -> Vec<ScheduleChange> {
+        std::mem::take(&mut self.schedule_changes)
+    }
+
+    /// Returns the ID correlating this message with the request it resulted
+    /// from.
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.metadata
+            .properties
+            .get(CORRELATION_ID_PROPERTY)
+            .and_then(Value::as_str)
+    }
+
+    /// Returns the stream this message is correlated with.
+    pub fn correlation_stream_name(&self) -> Option<&StreamName> {
+        self.metadata.correlation_stream_name.as_ref()
+    }
+
+    /// Returns the trace context this message was written in, if it was
+    /// traced.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        let traceparent = self
+            .metadata
+            .properties
+            .get(TRACEPARENT_PROPERTY)
+            .and_then(Value::as_str)?;
+        let tracestate = self
+            .metadata
+            .properties
+            .get(TRACESTATE_PROPERTY)
+            .and_then(Value::as_str);
+
+        Some(TraceContext {
+            traceparent: traceparent.to_string(),
+            tracestate: tracestate.map(str::to_string),
+        })
+    }
 }
@@ -1,13 +1,19 @@
 use std::collections::HashSet;
 
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use futures::future::{self};
 use futures::{FutureExt, StreamExt};
 use message_db::database::{MessageStore, SubscribeToCategoryOpts};
 use message_db::message::{GenericMessage, Message, MessageData};
+use quinn::{Connection, RecvStream, SendStream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 pub use thalo_macros::EventCollection;
 use tracing::{info, trace};
 
+use crate::subscription::{Subscribe, SubscriptionAck, SubscriptionRequest, SubscriptionResponse};
+
 pub trait EventCollection: Sized {
     fn entity_names() -> HashSet<&'static str>;
     fn deserialize_event(
@@ -63,22 +69,158 @@ where
         let mut message_stream = futures::stream::select_all(streams);
 
         while let Some(batch) = message_stream.next().await {
-            let messages = batch?;
-            for message in messages {
-                trace!(?message, "handling message");
-                match H::Event::deserialize_event(message)? {
-                    Some(event) => {
-                        let msg_type = event.msg_type.clone();
-                        self.handler.handle(event).await?;
-                        info!(msg_type, "handled event");
-                    }
-                    None => {
-                        trace!("ignoring unknown event");
-                    }
-                }
-            }
+            handle_messages(&self.handler, batch?).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Listens to events through subscriptions to a Thalo runtime over QUIC,
+/// rather than connecting to the message store directly.
+///
+/// The subscription options' position and identifier are passed on to the
+/// runtime, which records the position acknowledged by each identifier.
+pub struct QuicEventListener<H> {
+    connection: Connection,
+    handler: H,
+}
+
+struct Subscription {
+    send: SendStream,
+    recv: RecvStream,
+    /// Position of the last message of the previous batch, acknowledged when
+    /// the next batch is requested.
+    ack: Option<i64>,
+}
+
+impl<H> QuicEventListener<H> {
+    pub fn new(connection: Connection, handler: H) -> Self {
+        QuicEventListener {
+            connection,
+            handler,
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        category: &str,
+        opts: &SubscribeToCategoryOpts,
+    ) -> anyhow::Result<Subscription> {
+        let (mut send, recv) = self
+            .connection
+            .open_bi()
+            .await
+            .context("failed to open stream")?;
+        let request = SubscriptionRequest::Subscribe(Subscribe {
+            category: category.to_string(),
+            position: opts.position,
+            consumer_id: opts.identifier.clone(),
+        });
+        write_frame(&mut send, &request).await?;
+
+        Ok(Subscription {
+            send,
+            recv,
+            ack: None,
+        })
+    }
+}
+
+impl Subscription {
+    /// Acknowledges the previous batch and receives the next one.
+    async fn next_batch(&mut self) -> anyhow::Result<Vec<GenericMessage>> {
+        if let Some(position) = self.ack.take() {
+            write_frame(&mut self.send, &SubscriptionAck { position }).await?;
+        }
+
+        let resp: Result<SubscriptionResponse, String> = read_frame(&mut self.recv).await?;
+        let SubscriptionResponse::EventBatch(messages) = resp.map_err(|err| anyhow!(err))?;
+        self.ack = messages.last().map(|message| message.global_position);
+
+        Ok(messages)
+    }
+}
+
+#[async_trait]
+impl<H> EventListener for QuicEventListener<H>
+where
+    H: EventHandler + Send + Sync + 'static,
+    <H as EventHandler>::Event: Send,
+{
+    async fn listen(&self, opts: &SubscribeToCategoryOpts) -> anyhow::Result<()> {
+        let entity_names = H::Event::entity_names();
+        let subscriptions = future::join_all(
+            entity_names
+                .iter()
+                .map(|entity_name| self.subscribe(entity_name, opts)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+        // Batches are only acknowledged once the stream is polled again, after
+        // the previous batch was handled
+        let streams = subscriptions.into_iter().map(|subscription| {
+            futures::stream::try_unfold(subscription, |mut subscription| async move {
+                let messages = subscription.next_batch().await?;
+                Ok(Some((messages, subscription)))
+            })
+            .boxed()
+        });
+        let mut message_stream = futures::stream::select_all(streams);
+
+        while let Some(batch) = message_stream.next().await {
+            handle_messages(&self.handler, batch?).await?;
         }
 
         Ok(())
     }
 }
+
+async fn handle_messages<H>(handler: &H, messages: Vec<GenericMessage>) -> anyhow::Result<()>
+where
+    H: EventHandler,
+{
+    for message in messages {
+        trace!(?message, "handling message");
+        match H::Event::deserialize_event(message)? {
+            Some(event) => {
+                let msg_type = event.msg_type.clone();
+                handler.handle(event).await?;
+                info!(msg_type, "handled event");
+            }
+            None => {
+                trace!("ignoring unknown event");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a length-prefixed message, as expected by the runtime.
+async fn write_frame<T>(send: &mut SendStream, data: &T) -> anyhow::Result<()>
+where
+    T: Serialize,
+{
+    let data = rmp_serde::to_vec_named(data)?;
+    send.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    send.write_all(&data).await?;
+
+    Ok(())
+}
+
+async fn read_frame<T>(recv: &mut RecvStream) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+{
+    let mut size = [0u8; 4];
+    recv.read_exact(&mut size)
+        .await
+        .context("subscription closed")?;
+    let mut buffer = vec![0u8; u32::from_le_bytes(size) as usize];
+    recv.read_exact(&mut buffer).await?;
+
+    Ok(rmp_serde::from_slice(&buffer)?)
+}
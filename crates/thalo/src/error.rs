@@ -20,8 +20,14 @@ pub enum ErrorKind {
     DeserializeCommand(String),
     #[error("failed to deserialize event: {0}")]
     DeserializeEvent(String),
+    #[error("failed to deserialize state: {0}")]
+    DeserializeState(String),
+    #[error("failed to serialize command: {0}")]
+    SerializeCommand(String),
     #[error("failed to serialize event: {0}")]
     SerializeEvent(String),
+    #[error("failed to serialize state: {0}")]
+    SerializeState(String),
     #[error("unknown command")]
     UnknownCommand,
     #[error("unknown event")]
@@ -68,7 +74,10 @@ impl From<ErrorKind> for WitError {
             ErrorKind::Ignore(reason) => WitError::Ignore(reason.0),
             ErrorKind::DeserializeCommand(msg) => WitError::DeserializeCommand(msg),
             ErrorKind::DeserializeEvent(msg) => WitError::DeserializeEvent(msg),
+            ErrorKind::DeserializeState(msg) => WitError::DeserializeState(msg),
+            ErrorKind::SerializeCommand(msg) => WitError::SerializeCommand(msg),
             ErrorKind::SerializeEvent(msg) => WitError::SerializeEvent(msg),
+            ErrorKind::SerializeState(msg) => WitError::SerializeState(msg),
             ErrorKind::UnknownCommand => WitError::UnknownCommand,
             ErrorKind::UnknownEvent => WitError::UnknownEvent,
         }
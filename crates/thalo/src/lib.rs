@@ -6,7 +6,14 @@ mod command;
 pub mod consumer;
 mod error;
 mod event;
+#[doc(hidden)]
+pub mod log;
 mod macros;
+mod process_manager;
+mod projection;
+mod schedule;
+pub mod subscription;
+mod wit_types;
 
 pub use aggregate::{wit_aggregate, Aggregate};
 pub use command::*;
@@ -14,5 +21,11 @@ pub use error::{Error, ErrorKind};
 pub use event::*;
 // Re-exported for thalo_macros
 #[doc(hidden)]
+pub use message_db;
+pub use process_manager::{wit_process_manager, ProcessCommand, ProcessEvents, ProcessManager};
+pub use projection::{wit_projection, Entries, Projection};
+pub use schedule::{ScheduleChange, ScheduledCommand};
+// Re-exported for thalo_macros
+#[doc(hidden)]
 pub use serde_json;
-pub use thalo_macros::{Aggregate, Commands, Events};
+pub use thalo_macros::{Aggregate, Commands, Events, ProcessEvents};
@@ -0,0 +1,76 @@
+//! Logging from within modules, forwarded to the runtime's `tracing`
+//! subscriber through the host's `log` import, which every module world
+//! imports.
+//!
+//! Messages are logged with the [`trace!`], [`debug!`], [`info!`], [`warn!`]
+//! and [`error!`] macros, taking the same arguments as [`format!`].
+
+use std::fmt;
+
+pub use self::wit_log::log::Level;
+
+/// Bindings to the `log` import alone, shared by all module worlds.
+mod wit_log {
+    wit_bindgen_guest_rust::generate!({
+        path: "../../wit",
+        world: "log",
+    });
+}
+
+#[doc(hidden)]
+pub fn __log(level: Level, args: fmt::Arguments<'_>) {
+    let message = args.to_string();
+
+    #[cfg(target_arch = "wasm32")]
+    wit_log::log::log(level, &message);
+
+    // Outside of wasm, such as in tests, messages are logged directly
+    #[cfg(not(target_arch = "wasm32"))]
+    match level {
+        Level::Trace => tracing::trace!("{message}"),
+        Level::Debug => tracing::debug!("{message}"),
+        Level::Info => tracing::info!("{message}"),
+        Level::Warn => tracing::warn!("{message}"),
+        Level::Error => tracing::error!("{message}"),
+    }
+}
+
+/// Logs a message at the trace level.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::log::__log($crate::log::Level::Trace, ::std::format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at the debug level.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log::__log($crate::log::Level::Debug, ::std::format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at the info level.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log::__log($crate::log::Level::Info, ::std::format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at the warn level.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log::__log($crate::log::Level::Warn, ::std::format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at the error level.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log::__log($crate::log::Level::Error, ::std::format_args!($($arg)+))
+    };
+}
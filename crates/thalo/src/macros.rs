@@ -92,6 +92,242 @@ macro_rules! export_aggregate(($t:ident) => {
     }
 });
 
+/// Declares the process manager to be exported.
+#[macro_export]
+macro_rules! export_process_manager(($t:ident) => {
+    const _: () = {
+        #[doc(hidden)]
+        #[export_name = "process-manager#categories"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_process_manager_categories() -> i32 {
+            $crate::wit_process_manager::process_manager::call_categories::<$t>()
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_process-manager#categories"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_process_manager_categories(arg0: i32) {
+            $crate::wit_process_manager::process_manager::post_return_categories::<$t>(arg0)
+        }
+        #[doc(hidden)]
+        #[export_name = "process-manager#init"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_process_manager_init(arg0: i32, arg1: i32) -> i32 {
+            $crate::wit_process_manager::process_manager::call_init::<$t>(arg0, arg1)
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_process-manager#init"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_process_manager_init(arg0: i32) {
+            $crate::wit_process_manager::process_manager::post_return_init::<$t>(arg0)
+        }
+        #[doc(hidden)]
+        #[export_name = "process-manager#route"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_process_manager_route(
+            arg0: i32,
+            arg1: i32,
+            arg2: i32,
+            arg3: i32,
+            arg4: i64,
+            arg5: i64,
+            arg6: i32,
+            arg7: i32,
+            arg8: i64,
+            arg9: i32,
+            arg10: i32,
+            arg11: i32,
+            arg12: i32,
+        ) -> i32 {
+            $crate::wit_process_manager::process_manager::call_route::<$t>(
+                arg0,
+                arg1,
+                arg2,
+                arg3,
+                arg4,
+                arg5,
+                arg6,
+                arg7,
+                arg8,
+                arg9,
+                arg10,
+                arg11,
+                arg12,
+            )
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_process-manager#route"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_process_manager_route(arg0: i32) {
+            $crate::wit_process_manager::process_manager::post_return_route::<$t>(arg0)
+        }
+        #[doc(hidden)]
+        #[export_name = "process-manager#handle"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_process_manager_handle(
+            arg0: i32,
+            arg1: i32,
+            arg2: i32,
+            arg3: i32,
+            arg4: i32,
+            arg5: i32,
+            arg6: i64,
+            arg7: i64,
+            arg8: i32,
+            arg9: i32,
+            arg10: i64,
+            arg11: i32,
+            arg12: i32,
+            arg13: i32,
+            arg14: i32,
+        ) -> i32 {
+            $crate::wit_process_manager::process_manager::call_handle::<$t>(
+                arg0,
+                arg1,
+                arg2,
+                arg3,
+                arg4,
+                arg5,
+                arg6,
+                arg7,
+                arg8,
+                arg9,
+                arg10,
+                arg11,
+                arg12,
+                arg13,
+                arg14,
+            )
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_process-manager#handle"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_process_manager_handle(arg0: i32) {
+            $crate::wit_process_manager::process_manager::post_return_handle::<$t>(arg0)
+        }
+    };
+    #[used]
+    #[doc(hidden)]
+    #[cfg(target_arch = "wasm32")]
+    static __FORCE_SECTION_REF: fn() = __force_section_ref;
+    #[doc(hidden)]
+    #[cfg(target_arch = "wasm32")]
+    fn __force_section_ref() {
+        $crate::wit_process_manager::__link_section()
+    }
+});
+
+/// Declares the projection to be exported.
+#[macro_export]
+macro_rules! export_projection(($t:ident) => {
+    const _: () = {
+        #[doc(hidden)]
+        #[export_name = "projection#categories"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_projection_categories() -> i32 {
+            $crate::wit_projection::projection::call_categories::<$t>()
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_projection#categories"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_projection_categories(arg0: i32) {
+            $crate::wit_projection::projection::post_return_categories::<$t>(arg0)
+        }
+        #[doc(hidden)]
+        #[export_name = "projection#keys"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_projection_keys(
+            arg0: i32,
+            arg1: i32,
+            arg2: i32,
+            arg3: i32,
+            arg4: i64,
+            arg5: i64,
+            arg6: i32,
+            arg7: i32,
+            arg8: i64,
+            arg9: i32,
+            arg10: i32,
+            arg11: i32,
+            arg12: i32,
+        ) -> i32 {
+            $crate::wit_projection::projection::call_keys::<$t>(
+                arg0,
+                arg1,
+                arg2,
+                arg3,
+                arg4,
+                arg5,
+                arg6,
+                arg7,
+                arg8,
+                arg9,
+                arg10,
+                arg11,
+                arg12,
+            )
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_projection#keys"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_projection_keys(arg0: i32) {
+            $crate::wit_projection::projection::post_return_keys::<$t>(arg0)
+        }
+        #[doc(hidden)]
+        #[export_name = "projection#handle"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __export_projection_handle(
+            arg0: i32,
+            arg1: i32,
+            arg2: i32,
+            arg3: i32,
+            arg4: i64,
+            arg5: i64,
+            arg6: i32,
+            arg7: i32,
+            arg8: i64,
+            arg9: i32,
+            arg10: i32,
+            arg11: i32,
+            arg12: i32,
+            arg13: i32,
+            arg14: i32,
+        ) -> i32 {
+            $crate::wit_projection::projection::call_handle::<$t>(
+                arg0,
+                arg1,
+                arg2,
+                arg3,
+                arg4,
+                arg5,
+                arg6,
+                arg7,
+                arg8,
+                arg9,
+                arg10,
+                arg11,
+                arg12,
+                arg13,
+                arg14,
+            )
+        }
+        #[doc(hidden)]
+        #[export_name = "cabi_post_projection#handle"]
+        #[allow(non_snake_case)]
+        unsafe extern "C" fn __post_return_projection_handle(arg0: i32) {
+            $crate::wit_projection::projection::post_return_handle::<$t>(arg0)
+        }
+    };
+    #[used]
+    #[doc(hidden)]
+    #[cfg(target_arch = "wasm32")]
+    static __FORCE_SECTION_REF: fn() = __force_section_ref;
+    #[doc(hidden)]
+    #[cfg(target_arch = "wasm32")]
+    fn __force_section_ref() {
+        $crate::wit_projection::__link_section()
+    }
+});
+
 macro_rules! event_whitelist {
     ($aggregate:path, [ $( $event:path ),* $(,)? ]) => {[
         $( thalo_pg_eventstore::EventWhitelist {
@@ -0,0 +1,125 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{Context, Error, ErrorKind};
+
+/// A process manager, reacting to events from other aggregates by emitting
+/// commands.
+///
+/// Each process has its own state, with events routed to processes by ID.
+pub trait ProcessManager
+where
+    Self: Serialize + DeserializeOwned,
+{
+    type Event: ProcessEvents;
+
+    fn new(id: String) -> Result<Self, Error>;
+    /// Returns the ID of the process an event belongs to, or `None` to ignore
+    /// the event.
+    fn route(ctx: &Context, event: &Self::Event) -> Option<String>;
+    fn handle(&mut self, ctx: &Context, event: Self::Event) -> Result<Vec<ProcessCommand>, Error>;
+}
+
+/// Events a process manager or projection subscribes to.
+pub trait ProcessEvents: Sized {
+    /// Returns the event categories subscribed to.
+    fn categories() -> Vec<String>;
+    /// Deserializes an event, returning `None` if it's not subscribed to.
+    fn deserialize_event(
+        entity_name: &str,
+        event_type: &str,
+        payload: &[u8],
+    ) -> Result<Option<Self>, Error>;
+}
+
+/// A command emitted by a process manager.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessCommand {
+    /// Name of the aggregate handling the command.
+    pub aggregate: String,
+    /// ID of the aggregate instance.
+    pub id: String,
+    pub command: String,
+    /// Command payload in JSON.
+    pub payload: Vec<u8>,
+}
+
+impl ProcessCommand {
+    pub fn new<T>(
+        aggregate: impl Into<String>,
+        id: impl Into<String>,
+        command: impl Into<String>,
+        payload: &T,
+    ) -> Result<Self, Error>
+    where
+        T: Serialize,
+    {
+        Ok(ProcessCommand {
+            aggregate: aggregate.into(),
+            id: id.into(),
+            command: command.into(),
+            payload: serde_json::to_vec(payload)
+                .map_err(|err| ErrorKind::SerializeCommand(err.to_string()))?,
+        })
+    }
+}
+
+#[doc(hidden)]
+pub mod wit_process_manager {
+    wit_bindgen_guest_rust::generate!({
+        path: "../../wit",
+        world: "process-manager",
+    });
+
+    pub use process_manager::*;
+
+    use crate::wit_types::deserialize_event;
+    use crate::ProcessEvents;
+
+    impl<T> process_manager::ProcessManager for T
+    where
+        T: super::ProcessManager,
+    {
+        fn categories() -> Vec<String> {
+            T::Event::categories()
+        }
+
+        fn init(id: String) -> Result<State, Error> {
+            let state = T::new(id)?;
+            serde_json::to_vec(&state).map_err(|err| Error::SerializeState(err.to_string()))
+        }
+
+        fn route(event: Event) -> Result<Option<String>, Error> {
+            let ctx: crate::Context = event.ctx.try_into()?;
+            let event = deserialize_event::<T::Event>(&ctx, &event.event_type, &event.payload)?;
+            Ok(event.and_then(|event| T::route(&ctx, &event)))
+        }
+
+        fn handle(state: State, event: Event) -> Result<Output, Error> {
+            let mut state: T = serde_json::from_slice(&state)
+                .map_err(|err| Error::DeserializeState(err.to_string()))?;
+            let ctx: crate::Context = event.ctx.try_into()?;
+            let event = deserialize_event::<T::Event>(&ctx, &event.event_type, &event.payload)?;
+            let commands = match event {
+                Some(event) => state.handle(&ctx, event)?,
+                None => Vec::new(),
+            };
+
+            Ok(Output {
+                state: serde_json::to_vec(&state)
+                    .map_err(|err| Error::SerializeState(err.to_string()))?,
+                commands: commands
+                    .into_iter()
+                    .map(|command| Command {
+                        aggregate: command.aggregate,
+                        id: command.id,
+                        command: command.command,
+                        payload: command.payload,
+                    })
+                    .collect(),
+            })
+        }
+    }
+
+    crate::wit_types::impl_conversions!();
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Context, Error, ErrorKind, ProcessEvents};
+
+/// A projection, keeping a read model of events in a key-value table hosted
+/// by the runtime.
+///
+/// Events are handled in two steps: the keys of the entries an event updates
+/// are returned by `keys`, and the loaded entries are then updated by
+/// `handle`.
+pub trait Projection {
+    type Event: ProcessEvents;
+
+    /// Returns the keys of the entries an event reads or updates.
+    fn keys(ctx: &Context, event: &Self::Event) -> Vec<String>;
+    fn handle(ctx: &Context, event: Self::Event, entries: &mut Entries) -> Result<(), Error>;
+}
+
+/// Entries of a projection's table, loaded for the keys of an event.
+///
+/// Values are serialized as JSON.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entries {
+    values: BTreeMap<String, Vec<u8>>,
+    changes: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+impl Entries {
+    /// Returns the value of an entry, or `None` if it doesn't exist or wasn't
+    /// loaded.
+    pub fn get<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let value = match self.changes.get(key) {
+            Some(change) => change.as_ref(),
+            None => self.values.get(key),
+        };
+        value
+            .map(|value| serde_json::from_slice(value))
+            .transpose()
+            .map_err(|err| ErrorKind::DeserializeState(err.to_string()).into())
+    }
+
+    /// Sets the value of an entry.
+    pub fn set<T>(&mut self, key: impl Into<String>, value: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let value =
+            serde_json::to_vec(value).map_err(|err| ErrorKind::SerializeState(err.to_string()))?;
+        self.changes.insert(key.into(), Some(value));
+        Ok(())
+    }
+
+    /// Deletes an entry.
+    pub fn delete(&mut self, key: impl Into<String>) {
+        self.changes.insert(key.into(), None);
+    }
+}
+
+#[doc(hidden)]
+pub mod wit_projection {
+    wit_bindgen_guest_rust::generate!({
+        path: "../../wit",
+        world: "projection",
+    });
+
+    pub use projection::*;
+
+    use crate::wit_types::deserialize_event;
+    use crate::ProcessEvents;
+
+    impl<T> projection::Projection for T
+    where
+        T: super::Projection,
+    {
+        fn categories() -> Vec<String> {
+            T::Event::categories()
+        }
+
+        fn keys(event: Event) -> Result<Vec<String>, Error> {
+            let ctx: crate::Context = event.ctx.try_into()?;
+            let event = deserialize_event::<T::Event>(&ctx, &event.event_type, &event.payload)?;
+            Ok(event.map(|event| T::keys(&ctx, &event)).unwrap_or_default())
+        }
+
+        fn handle(event: Event, entries: Vec<Entry>) -> Result<Vec<Operation>, Error> {
+            let ctx: crate::Context = event.ctx.try_into()?;
+            let event =
+                match deserialize_event::<T::Event>(&ctx, &event.event_type, &event.payload)? {
+                    Some(event) => event,
+                    None => return Ok(Vec::new()),
+                };
+
+            let mut entries = super::Entries {
+                values: entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect(),
+                changes: Default::default(),
+            };
+            T::handle(&ctx, event, &mut entries)?;
+
+            Ok(entries
+                .changes
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => Operation::Set(Entry { key, value }),
+                    None => Operation::Delete(key),
+                })
+                .collect())
+        }
+    }
+
+    crate::wit_types::impl_conversions!();
+}
@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ErrorKind};
+
+/// A command submitted by the runtime once it comes due.
+///
+/// Schedule IDs are scoped to the aggregate instance scheduling the command,
+/// and scheduling a command with the ID of one of its pending schedules
+/// replaces it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledCommand {
+    /// ID of the schedule, used to replace or cancel it.
+    pub id: String,
+    /// Name of the aggregate handling the command.
+    pub aggregate: String,
+    /// ID of the aggregate instance.
+    pub entity_id: String,
+    pub command: String,
+    /// Command payload in JSON.
+    pub payload: Vec<u8>,
+    /// Time at which the command is submitted.
+    pub due: DateTime<Utc>,
+}
+
+/// A change to the scheduled commands, requested while handling a command.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleChange {
+    Schedule(ScheduledCommand),
+    Cancel(String),
+}
+
+impl ScheduledCommand {
+    pub fn new<T>(
+        id: impl Into<String>,
+        aggregate: impl Into<String>,
+        entity_id: impl Into<String>,
+        command: impl Into<String>,
+        payload: &T,
+        due: DateTime<Utc>,
+    ) -> Result<Self, Error>
+    where
+        T: Serialize,
+    {
+        Ok(ScheduledCommand {
+            id: id.into(),
+            aggregate: aggregate.into(),
+            entity_id: entity_id.into(),
+            command: command.into(),
+            payload: serde_json::to_vec(payload)
+                .map_err(|err| ErrorKind::SerializeCommand(err.to_string()))?,
+            due,
+        })
+    }
+}
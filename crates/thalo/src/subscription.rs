@@ -0,0 +1,42 @@
+//! Messages of category subscriptions over the runtime's QUIC interface,
+//! shared by the runtime and consumers.
+
+use message_db::message::GenericMessage;
+use serde::{Deserialize, Serialize};
+
+/// Subscribes to a category, keeping the stream open to push batches of
+/// messages as they are written.
+///
+/// Each batch is acknowledged by sending a [`SubscriptionAck`] on the same
+/// stream once handled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscribe {
+    pub category: String,
+    /// Global position to start from, defaulting to after the consumer's
+    /// last acknowledged position.
+    #[serde(default)]
+    pub position: Option<i64>,
+    /// Consumer ID to record acknowledged positions under.
+    #[serde(default)]
+    pub consumer_id: Option<String>,
+}
+
+/// Acknowledges the messages of a subscription up to a global position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriptionAck {
+    pub position: i64,
+}
+
+/// The requests sent by consumers, encoded as the same variants of the
+/// runtime's requests.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubscriptionRequest {
+    Subscribe(Subscribe),
+}
+
+/// The responses received by consumers, encoded as the same variants of the
+/// runtime's responses.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubscriptionResponse {
+    EventBatch(Vec<GenericMessage>),
+}
@@ -0,0 +1,76 @@
+//! Conversions of the `types` interface shared by the process manager and
+//! projection worlds.
+//!
+//! Each world's bindings generate their own copy of the interface's types, so
+//! the conversions are implemented within each binding module by
+//! [`impl_conversions`].
+
+use crate::{Context, Error, ProcessEvents};
+
+/// Deserializes an event of a subscribed category, returning `None` if it's
+/// not subscribed to.
+pub(crate) fn deserialize_event<E>(
+    ctx: &Context,
+    event_type: &str,
+    payload: &[u8],
+) -> Result<Option<E>, Error>
+where
+    E: ProcessEvents,
+{
+    E::deserialize_event(&ctx.stream_name.category.entity_name, event_type, payload)
+}
+
+/// Implements the conversions between a world's `types` and the crate's
+/// types.
+macro_rules! impl_conversions {
+    () => {
+        impl TryFrom<types::Context> for crate::Context {
+            type Error = types::Error;
+
+            fn try_from(ctx: types::Context) -> Result<Self, Self::Error> {
+                // Shares the aggregate world's context conversion
+                crate::wit_aggregate::Context {
+                    id: ctx.id,
+                    stream_name: ctx.stream_name,
+                    position: ctx.position,
+                    global_position: ctx.global_position,
+                    metadata: ctx.metadata,
+                    time: ctx.time,
+                }
+                .try_into()
+                .map_err(types::Error::from)
+            }
+        }
+
+        impl From<crate::Error> for types::Error {
+            fn from(err: crate::Error) -> Self {
+                crate::wit_aggregate::Error::from(err).into()
+            }
+        }
+
+        impl From<crate::wit_aggregate::Error> for types::Error {
+            fn from(err: crate::wit_aggregate::Error) -> Self {
+                use crate::wit_aggregate::Error as AggregateError;
+
+                match err {
+                    AggregateError::Command(msg) => Self::Handle(msg),
+                    AggregateError::Ignore(Some(reason)) => {
+                        Self::Handle(format!("event ignored with reason: '{reason}'"))
+                    }
+                    AggregateError::Ignore(None) => Self::Handle("event ignored".to_string()),
+                    AggregateError::DeserializeCommand(msg) => Self::DeserializeCommand(msg),
+                    AggregateError::DeserializeContext(msg) => Self::DeserializeContext(msg),
+                    AggregateError::DeserializeEvent(msg) => Self::DeserializeEvent(msg),
+                    AggregateError::DeserializeState(msg) => Self::DeserializeState(msg),
+                    AggregateError::SerializeCommand(msg) => Self::SerializeCommand(msg),
+                    AggregateError::SerializeEvent(msg) => Self::SerializeEvent(msg),
+                    AggregateError::SerializeState(msg) => Self::SerializeState(msg),
+                    AggregateError::UnknownCommand => Self::UnknownCommand,
+                    AggregateError::UnknownEvent => Self::UnknownEvent,
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use impl_conversions;
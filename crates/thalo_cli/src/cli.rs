@@ -2,8 +2,14 @@
 //!
 //! Checkout the `README.md` for guidance.
 
+mod dead_letter;
+mod events;
 mod execute;
+mod pin;
 mod publish;
+mod query;
+mod schedule;
+mod state;
 
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
@@ -15,11 +21,18 @@ use clap::{Parser, Subcommand};
 use quinn::RecvStream;
 use thalo_runtime::interface::message::{receive, ExecutedResult, Response};
 use thalo_runtime::interface::quic::ALPN_QUIC_HTTP;
+use thalo_runtime::runtime::EventPage;
 use tracing::{error, info, trace};
 use url::Url;
 
+use self::dead_letter::DeadLetters;
+use self::events::Events;
 use self::execute::Execute;
+use self::pin::Pin;
 use self::publish::Publish;
+use self::query::Query;
+use self::schedule::Schedules;
+use self::state::State;
 
 /// Thalo client
 #[derive(Parser, Debug)]
@@ -43,8 +56,14 @@ struct Cli {
 
 #[derive(Subcommand, Clone, Debug)]
 enum Commands {
+    DeadLetters(DeadLetters),
+    Events(Events),
     Execute(Execute),
+    Pin(Pin),
     Publish(Publish),
+    Query(Query),
+    Schedules(Schedules),
+    State(State),
 }
 
 pub async fn run() -> Result<()> {
@@ -110,10 +129,18 @@ pub async fn run() -> Result<()> {
         .map_err(|e| anyhow!("failed to open stream: {}", e))?;
 
     match cli.command.clone() {
+        Commands::DeadLetters(dead_letters) => {
+            dead_letters.dead_letters(&mut send, &mut recv).await?
+        }
+        Commands::Events(events) => events.events(&conn, &mut send, &mut recv).await?,
         Commands::Execute(execute) => {
             execute.clone().execute(&mut send, &mut recv).await?;
         }
+        Commands::Pin(pin) => pin.pin(&mut send, &mut recv).await?,
         Commands::Publish(publish) => publish.publish(&mut send, &mut recv).await?,
+        Commands::Query(query) => query.query(&mut send, &mut recv).await?,
+        Commands::Schedules(schedules) => schedules.schedules(&mut send, &mut recv).await?,
+        Commands::State(state) => state.state(&mut send, &mut recv).await?,
     }
 
     let _ = send.finish().await;
@@ -132,19 +159,83 @@ async fn handle_response(recv: &mut RecvStream) -> Result<()> {
     let resp = resp_result.map_err(|err| anyhow!("{err}"))?;
     match resp {
         Response::Executed(executed_result) => match executed_result {
-            ExecutedResult::Events(events) => {
+            ExecutedResult::Accepted(events) => {
                 println!("executed with {} events:", events.len());
                 for event in &events {
-                    println!("    {}  {}", event.msg_type, event.data);
+                    println!("    {}  {}", event.event_type, event.data);
                 }
             }
-            ExecutedResult::TimedOut => {
-                println!("timed out");
+            ExecutedResult::Ignored(reason) => match reason {
+                Some(reason) => println!("ignored: {reason}"),
+                None => println!("ignored"),
+            },
+            ExecutedResult::Failed(errors) => {
+                println!("failed: {}", errors.join(": "));
+            }
+            ExecutedResult::Submitted => {
+                println!("submitted to the node handling the entity");
             }
         },
         Response::Published => {
             println!("published");
         }
+        Response::Pinned => {
+            println!("pinned");
+        }
+        Response::DeadLetters(entries) => {
+            println!("{} dead letters:", entries.len());
+            for entry in &entries {
+                println!(
+                    "    {}  {}  {}  {}",
+                    entry.id,
+                    entry.dead_letter.ctx.stream_name,
+                    entry.dead_letter.command,
+                    entry.dead_letter.errors.first().map_or("", String::as_str)
+                );
+            }
+        }
+        Response::DeadLetter(entry) => {
+            let dead_letter = &entry.dead_letter;
+            println!("id:              {}", entry.id);
+            println!("command id:      {}", dead_letter.ctx.id);
+            println!("stream:          {}", dead_letter.ctx.stream_name);
+            println!("command:         {}", dead_letter.command);
+            println!("payload:         {}", dead_letter.payload);
+            match &dead_letter.module_version {
+                Some(version) => println!("module version:  {version}"),
+                None => println!("module version:  unknown"),
+            }
+            println!("errors:");
+            for err in &dead_letter.errors {
+                println!("    {err}");
+            }
+        }
+        Response::DeadLetterRetried { command_id } => {
+            println!("retried as command {command_id}");
+        }
+        Response::DeadLetterDiscarded => {
+            println!("discarded");
+        }
+        Response::ProjectionEntry(value) => match value {
+            Some(value) => println!("{value:#}"),
+            None => println!("not found"),
+        },
+        Response::ScheduleCancelled => {
+            println!("cancelled");
+        }
+        Response::State(entity_state) => {
+            println!("module version:  {}", entity_state.module_version);
+            println!("position:        {}", entity_state.position);
+            println!("{:#}", entity_state.state);
+        }
+        Response::Events(EventPage { messages, .. }) | Response::EventBatch(messages) => {
+            for message in &messages {
+                println!(
+                    "{}  {}  {}  {}",
+                    message.position, message.stream_name, message.msg_type, message.data
+                );
+            }
+        }
     }
 
     Ok(())
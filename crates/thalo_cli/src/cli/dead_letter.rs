@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use quinn::{RecvStream, SendStream};
+use thalo_runtime::interface::message::{pack, Request};
+use thalo_runtime::module::ModuleName;
+use uuid::Uuid;
+
+use super::handle_response;
+
+/// Manage commands which failed to be handled
+#[derive(Args, Clone, Debug)]
+pub struct DeadLetters {
+    #[command(subcommand)]
+    command: DeadLetterCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum DeadLetterCommand {
+    /// List unresolved dead letters of a module
+    List {
+        /// Name of aggregate
+        name: ModuleName,
+    },
+    /// Show a dead letter in detail
+    Inspect {
+        /// Name of aggregate
+        name: ModuleName,
+        /// ID of dead letter
+        id: Uuid,
+    },
+    /// Resubmit a dead lettered command
+    Retry {
+        /// Name of aggregate
+        name: ModuleName,
+        /// ID of dead letter
+        id: Uuid,
+    },
+    /// Discard a dead lettered command
+    Discard {
+        /// Name of aggregate
+        name: ModuleName,
+        /// ID of dead letter
+        id: Uuid,
+    },
+}
+
+impl DeadLetters {
+    pub async fn dead_letters(self, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+        let request = match self.command {
+            DeadLetterCommand::List { name } => Request::ListDeadLetters { name },
+            DeadLetterCommand::Inspect { name, id } => Request::InspectDeadLetter { name, id },
+            DeadLetterCommand::Retry { name, id } => Request::RetryDeadLetter { name, id },
+            DeadLetterCommand::Discard { name, id } => Request::DiscardDeadLetter { name, id },
+        };
+        let mut request = pack(&request)?;
+
+        send.write_all_chunks(&mut request)
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+
+        handle_response(recv).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::Args;
+use quinn::{Connection, RecvStream, SendStream};
+use thalo_runtime::interface::message::{pack, receive, Request, Response};
+use thalo_runtime::module::ModuleName;
+use thalo_runtime::runtime::EventPage;
+
+/// Interval between reads once caught up when following events.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Print the events of an aggregate instance, or of all its instances
+#[derive(Args, Clone, Debug)]
+pub struct Events {
+    /// Name of aggregate
+    name: ModuleName,
+    /// ID of aggregate instance, or all instances if omitted
+    id: Option<String>,
+    /// Keep waiting for new events
+    #[clap(short, long)]
+    follow: bool,
+    /// Position to read from, the stream position for an instance or the
+    /// global position otherwise
+    #[clap(long, default_value_t = 0)]
+    position: i64,
+    /// Number of events read per request
+    #[clap(long)]
+    batch_size: Option<i64>,
+    /// Only print events of this type, can be repeated
+    #[clap(long = "event-type")]
+    event_types: Vec<String>,
+}
+
+impl Events {
+    pub async fn events(
+        self,
+        conn: &Connection,
+        send: &mut SendStream,
+        recv: &mut RecvStream,
+    ) -> Result<()> {
+        let mut page = self.read_page(send, recv, self.position).await?;
+        loop {
+            for message in &page.messages {
+                let position = match self.id {
+                    Some(_) => message.position,
+                    None => message.global_position,
+                };
+                println!(
+                    "{position}  {}  {}  {}",
+                    message.stream_name, message.msg_type, message.data
+                );
+            }
+
+            if page.caught_up {
+                if !self.follow {
+                    break;
+                }
+                tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+            }
+
+            let (mut send, mut recv) = conn
+                .open_bi()
+                .await
+                .map_err(|e| anyhow!("failed to open stream: {}", e))?;
+            page = self
+                .read_page(&mut send, &mut recv, page.next_position)
+                .await?;
+            let _ = send.finish().await;
+        }
+
+        Ok(())
+    }
+
+    async fn read_page(
+        &self,
+        send: &mut SendStream,
+        recv: &mut RecvStream,
+        position: i64,
+    ) -> Result<EventPage> {
+        let request = match &self.id {
+            Some(id) => Request::ReadStream {
+                name: self.name.clone(),
+                id: id.clone(),
+                position,
+                batch_size: self.batch_size,
+                event_types: self.event_types.clone(),
+            },
+            None => Request::ReadCategory {
+                name: self.name.clone(),
+                position,
+                batch_size: self.batch_size,
+                event_types: self.event_types.clone(),
+            },
+        };
+        let mut request = pack(&request)?;
+
+        send.write_all_chunks(&mut request)
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+
+        let resp: Result<Response, String> = receive(recv).await?;
+        match resp.map_err(|err| anyhow!("{err}"))? {
+            Response::Events(page) => Ok(page),
+            resp => Err(anyhow!("unexpected response: {resp:?}")),
+        }
+    }
+}
@@ -3,7 +3,9 @@ use std::str;
 use anyhow::{anyhow, Result};
 use clap::Args;
 use quinn::{RecvStream, SendStream};
+use semver::VersionReq;
 use serde_json::Value;
+use thalo::TraceContext;
 use thalo_runtime::interface::message::{pack, Request};
 use thalo_runtime::module::ModuleName;
 use uuid::Uuid;
@@ -17,13 +19,25 @@ pub struct Execute {
     name: ModuleName,
     /// ID of aggregate instance
     id: String,
-    /// Command to execute
+    /// Unique command ID, used to deduplicate retried submissions
     #[clap(long)]
     command_id: Option<Uuid>,
     /// Command to execute
     command: String,
     /// Command data in JSON
     data: Payload,
+    /// Module version requirement to handle the command with
+    #[clap(long)]
+    module_version: Option<VersionReq>,
+    /// Correlation ID to continue, defaults to the command ID
+    #[clap(long)]
+    correlation_id: Option<String>,
+    /// W3C traceparent to continue a trace from
+    #[clap(long)]
+    traceparent: Option<String>,
+    /// W3C tracestate accompanying the traceparent
+    #[clap(long, requires = "traceparent")]
+    tracestate: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -34,8 +48,15 @@ impl Execute {
         let request = Request::Execute {
             name: self.name,
             id: self.id,
+            command_id: self.command_id,
             command: self.command,
             data: self.data.0,
+            module_version: self.module_version,
+            correlation_id: self.correlation_id,
+            trace_context: self.traceparent.map(|traceparent| TraceContext {
+                traceparent,
+                tracestate: self.tracestate,
+            }),
         };
         let mut request = pack(&request)?;
 
@@ -0,0 +1,35 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use quinn::{RecvStream, SendStream};
+use semver::VersionReq;
+use thalo_runtime::interface::message::{pack, Request};
+use thalo_runtime::module::ModuleName;
+
+use super::handle_response;
+
+/// Pin a module to a version requirement
+#[derive(Args, Clone, Debug)]
+pub struct Pin {
+    /// Name of aggregate
+    name: ModuleName,
+    /// Version requirement, such as "^1.2"
+    version: VersionReq,
+}
+
+impl Pin {
+    pub async fn pin(self, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+        let request = Request::Pin {
+            name: self.name,
+            version: self.version,
+        };
+        let mut request = pack(&request)?;
+
+        send.write_all_chunks(&mut request)
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+
+        handle_response(recv).await?;
+
+        Ok(())
+    }
+}
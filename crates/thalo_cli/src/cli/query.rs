@@ -0,0 +1,34 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use quinn::{RecvStream, SendStream};
+use thalo_runtime::interface::message::{pack, Request};
+use thalo_runtime::module::ModuleName;
+
+use super::handle_response;
+
+/// Query an entry of a projection
+#[derive(Args, Clone, Debug)]
+pub struct Query {
+    /// Name of projection
+    name: ModuleName,
+    /// Key of entry
+    key: String,
+}
+
+impl Query {
+    pub async fn query(self, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+        let request = Request::QueryProjection {
+            name: self.name,
+            key: self.key,
+        };
+        let mut request = pack(&request)?;
+
+        send.write_all_chunks(&mut request)
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+
+        handle_response(recv).await?;
+
+        Ok(())
+    }
+}
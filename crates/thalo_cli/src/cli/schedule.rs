@@ -0,0 +1,52 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use quinn::{RecvStream, SendStream};
+use thalo_runtime::interface::message::{pack, Request};
+use thalo_runtime::module::ModuleName;
+
+use super::handle_response;
+
+/// Manage scheduled commands
+#[derive(Args, Clone, Debug)]
+pub struct Schedules {
+    #[command(subcommand)]
+    command: ScheduleCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum ScheduleCommand {
+    /// Cancel a pending scheduled command
+    Cancel {
+        /// Name of the aggregate which scheduled the command
+        name: ModuleName,
+        /// ID of the aggregate instance which scheduled the command
+        entity_id: String,
+        /// ID of schedule
+        id: String,
+    },
+}
+
+impl Schedules {
+    pub async fn schedules(self, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+        let request = match self.command {
+            ScheduleCommand::Cancel {
+                name,
+                entity_id,
+                id,
+            } => Request::CancelSchedule {
+                name,
+                entity_id,
+                id,
+            },
+        };
+        let mut request = pack(&request)?;
+
+        send.write_all_chunks(&mut request)
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+
+        handle_response(recv).await?;
+
+        Ok(())
+    }
+}
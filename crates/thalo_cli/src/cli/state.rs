@@ -0,0 +1,38 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use quinn::{RecvStream, SendStream};
+use thalo_runtime::interface::message::{pack, Request};
+use thalo_runtime::module::ModuleName;
+
+use super::handle_response;
+
+/// Show the current state of an aggregate
+#[derive(Args, Clone, Debug)]
+pub struct State {
+    /// Name of aggregate
+    name: ModuleName,
+    /// ID of aggregate instance
+    id: String,
+    /// Rebuild the state as of a stream position
+    #[clap(long)]
+    at_position: Option<i64>,
+}
+
+impl State {
+    pub async fn state(self, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+        let request = Request::GetState {
+            name: self.name,
+            id: self.id,
+            at_position: self.at_position,
+        };
+        let mut request = pack(&request)?;
+
+        send.write_all_chunks(&mut request)
+            .await
+            .map_err(|e| anyhow!("failed to send request: {}", e))?;
+
+        handle_response(recv).await?;
+
+        Ok(())
+    }
+}
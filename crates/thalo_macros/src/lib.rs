@@ -2,6 +2,7 @@ mod aggregate;
 mod commands;
 mod event_collection;
 mod events;
+mod process_events;
 
 use proc_macro::TokenStream;
 use proc_macro_error::{abort, proc_macro_error};
@@ -11,6 +12,7 @@ use crate::aggregate::DeriveAggregate;
 use crate::commands::DeriveCommands;
 use crate::event_collection::DeriveEventCollection;
 use crate::events::DeriveEvents;
+use crate::process_events::DeriveProcessEvents;
 
 #[proc_macro_error]
 #[proc_macro_derive(Aggregate, attributes(aggregate))]
@@ -36,6 +38,12 @@ pub fn commands(input: TokenStream) -> TokenStream {
     expand_derive_macro::<DeriveCommands>(input)
 }
 
+#[proc_macro_error]
+#[proc_macro_derive(ProcessEvents)]
+pub fn process_events(input: TokenStream) -> TokenStream {
+    expand_derive_macro::<DeriveProcessEvents>(input)
+}
+
 trait DeriveMacro: Sized {
     fn expand(&self) -> proc_macro2::TokenStream;
     fn parse_input(input: DeriveInput) -> syn::Result<Self>;
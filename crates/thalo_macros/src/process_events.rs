@@ -0,0 +1,141 @@
+use proc_macro2::TokenStream;
+use quote::{quote};
+use syn::{
+    spanned::Spanned,
+    DeriveInput,
+};
+
+use crate::DeriveMacro;
+
+pub struct DeriveProcessEvents {
+    ident: syn::Ident,
+    variants: Vec<Variant>,
+}
+
+struct Variant {
+    variant_ident: syn::Ident,
+    event_path: syn::Path,
+}
+
+impl DeriveProcessEvents {
+    fn expand_impl_process_events(&self) -> TokenStream {
+        let Self {
+            ident,
+            variants,
+        } = self;
+
+        let categories = variants.iter().map(|Variant { event_path, .. }| {
+            quote! {
+                ::thalo::message_db::stream_name::Category::normalize(
+                    <<#event_path as ::thalo::Event>::Aggregate as ::thalo::Aggregate>::aggregate_type()
+                )
+            }
+        });
+
+        let deserialize_events = variants.iter().map(|Variant { variant_ident, event_path }| {
+            quote! {
+                if entity_name
+                    == ::thalo::message_db::stream_name::Category::normalize(
+                        <<#event_path as ::thalo::Event>::Aggregate as ::thalo::Aggregate>::aggregate_type()
+                    )
+                    && event_type == <#event_path as ::thalo::Event>::event_type()
+                {
+                    let event: #event_path = ::thalo::serde_json::from_slice(payload)
+                        .map_err(|err| ::thalo::ErrorKind::DeserializeEvent(err.to_string()))?;
+                    return ::std::result::Result::Ok(::std::option::Option::Some(#ident::#variant_ident(event)));
+                }
+            }
+        });
+
+        quote! {
+            #[automatically_derived]
+            impl ::thalo::ProcessEvents for #ident {
+                fn categories() -> ::std::vec::Vec<::std::string::String> {
+                    let mut categories = ::std::vec![
+                        #( #categories ),*
+                    ];
+                    categories.sort();
+                    categories.dedup();
+                    categories
+                }
+
+                fn deserialize_event(
+                    entity_name: &str,
+                    event_type: &str,
+                    payload: &[u8],
+                ) -> ::std::result::Result<::std::option::Option<Self>, ::thalo::Error> {
+                    #( #deserialize_events )*
+
+                    ::std::result::Result::Ok(::std::option::Option::None)
+                }
+            }
+        }
+    }
+}
+
+impl DeriveMacro for DeriveProcessEvents {
+    fn expand(&self) -> TokenStream {
+        self.expand_impl_process_events()
+    }
+
+    fn parse_input(input: DeriveInput) -> syn::Result<Self> {
+        let span = input.span();
+        let variants = match input.data {
+            syn::Data::Enum(syn::DataEnum { variants, .. }) => {
+                variants.into_iter().map(|variant| {
+                    match variant.fields {
+                        syn::Fields::Unnamed(fields_unnamed) if fields_unnamed.unnamed.len() == 1 => {
+                            let field = fields_unnamed
+                                .unnamed
+                                .into_iter()
+                                .next()
+                                .expect("length already checked");
+                            let ty = match field.ty {
+                                syn::Type::Path(type_path) => {
+                                    type_path.path
+                                }
+                                ty => {
+                                    return Err(syn::Error::new(
+                                        ty.span(),
+                                        "Variant should be a path to an event struct. Eg. `Deposited(DepositedEvent)`",
+                                    ));
+                                }
+                            };
+
+                            Ok(Variant { variant_ident: variant.ident, event_path: ty })
+                        }
+                        syn::Fields::Unnamed(fields_unnamed) => {
+                            Err(syn::Error::new(
+                                fields_unnamed.span(),
+                                "DeriveProcessEvents variants must contain only one unnamed field. Eg. `Deposited(DepositedEvent)`",
+                            ))
+                        }
+                        syn::Fields::Named(fields_named) => {
+                            Err(syn::Error::new(
+                                fields_named.span(),
+                                "DeriveProcessEvents variants cannot have named fields. Variants must use unnamed fields. Eg. `Deposited(DepositedEvent)`",
+                            ))
+                        }
+                        syn::Fields::Unit => {
+                            Err(syn::Error::new(
+                                variant.fields.span(),
+                                "DeriveProcessEvents expected an unamed field. Eg. `Deposited(DepositedEvent)`",
+                            ))
+                        }
+                    }
+                }).collect::<Result<Vec<_>, _>>()?
+            },
+            _ => {
+                return Err(syn::Error::new(
+                    span,
+                    "DeriveProcessEvents can only be used with enums",
+                ));
+            }
+        };
+
+        Ok(DeriveProcessEvents {
+            ident: input.ident,
+            variants,
+        })
+    }
+}
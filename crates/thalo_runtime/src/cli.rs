@@ -1,18 +1,40 @@
+use std::collections::HashMap;
+use std::fs::File;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::str;
+use std::time::Duration;
+use std::{future, io};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use message_db::database::MessageStore;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use opentelemetry::sdk::export::trace::stdout;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use semver::VersionReq;
 use thalo_registry::Registry;
+use thalo_runtime::config::{Config, ConsumerGroup, ModuleLimits, RetryPolicy};
 use thalo_runtime::interface::quic::load_certs;
 use thalo_runtime::interface::{self};
+use thalo_runtime::module::ModuleName;
 use thalo_runtime::runtime::Runtime;
+use thalo_runtime::telemetry;
+use tokio::signal;
+use tracing::{error, info};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
 /// Thalo runtime for event sourcing systems
 #[derive(Parser, Debug)]
 #[command(name = "thalo", version, about, long_about = None)]
-struct Cli {
+pub struct Cli {
     /// file to log TLS keys to for debugging
     #[clap(long)]
     keylog: bool,
@@ -32,14 +54,244 @@ struct Cli {
     /// Address to listen on
     #[clap(long, default_value = "[::1]:4433")]
     listen: SocketAddr,
+    /// Address to serve Prometheus metrics on, such as "[::1]:9000"
+    #[clap(long)]
+    metrics_listen: Option<SocketAddr>,
+    /// OTLP gRPC endpoint to export traces to, such as "http://localhost:4317"
+    #[clap(long, conflicts_with = "trace_file")]
+    otlp_endpoint: Option<String>,
+    /// File to write traces to for local debugging, or "-" for stdout
+    #[clap(long)]
+    trace_file: Option<PathBuf>,
+    /// Number of events between aggregate state snapshots, or 0 to disable
+    #[clap(long, default_value_t = 100)]
+    snapshot_interval: u64,
+    /// Maximum number of entity command handlers kept in memory
+    #[clap(long, default_value = "10000")]
+    handler_capacity: NonZeroUsize,
+    /// Number of command router shards, defaults to the number of CPUs
+    #[clap(long)]
+    router_shards: Option<NonZeroUsize>,
+    /// Maximum number of retries for commands conflicting with concurrent
+    /// writes
+    #[clap(long, default_value_t = 5)]
+    conflict_max_retries: u32,
+    /// Maximum time in seconds to wait for queued commands to be handled when
+    /// shutting down
+    #[clap(long, default_value_t = 30)]
+    shutdown_timeout_secs: u64,
+    /// Index of this node in its consumer group, starting from 0
+    #[clap(long, requires = "consumer_group_size")]
+    consumer_group_member: Option<u32>,
+    /// Number of nodes in the consumer group, splitting entity streams
+    /// between them
+    #[clap(long, requires = "consumer_group_member")]
+    consumer_group_size: Option<u32>,
+    /// Pin a module to a version requirement, such as "counter=^1.2"
+    #[clap(long)]
+    module_version: Vec<ModuleArg<VersionReq>>,
+    /// Maximum time in milliseconds a single call into a module may run for
+    #[clap(long, default_value_t = 5000)]
+    execution_budget_ms: u64,
+    /// Override the execution budget of a module, such as "counter=500"
+    #[clap(long)]
+    module_execution_budget_ms: Vec<ModuleArg<u64>>,
+    /// Maximum number of 64 KiB pages a module's memory may grow to
+    #[clap(long, default_value_t = 1024)]
+    max_memory_pages: u32,
+    /// Override the maximum memory pages of a module, such as "counter=256"
+    #[clap(long)]
+    module_max_memory_pages: Vec<ModuleArg<u32>>,
+    /// Maximum number of elements a module's tables may grow to
+    #[clap(long, default_value_t = 10_000)]
+    max_table_elements: u32,
+    /// Override the maximum table elements of a module, such as "counter=1000"
+    #[clap(long)]
+    module_max_table_elements: Vec<ModuleArg<u32>>,
+    /// Maximum number of core instances in a module's store
+    #[clap(long, default_value_t = 100)]
+    max_instances: usize,
+    /// Override the maximum core instances of a module, such as "counter=10"
+    #[clap(long)]
+    module_max_instances: Vec<ModuleArg<usize>>,
+    /// Maximum number of tables in a module's store
+    #[clap(long, default_value_t = 100)]
+    max_tables: usize,
+    /// Override the maximum tables of a module, such as "counter=10"
+    #[clap(long)]
+    module_max_tables: Vec<ModuleArg<usize>>,
+    /// Maximum number of linear memories in a module's store
+    #[clap(long, default_value_t = 100)]
+    max_memories: usize,
+    /// Override the maximum memories of a module, such as "counter=10"
+    #[clap(long)]
+    module_max_memories: Vec<ModuleArg<usize>>,
+    /// Number of instances per module executing entities in parallel
+    #[clap(long, default_value = "8")]
+    instance_pool_size: NonZeroUsize,
+    /// Override the instance pool size of a module, such as "counter=32"
+    #[clap(long)]
+    module_instance_pool_size: Vec<ModuleArg<NonZeroUsize>>,
+    /// Number of instance slots reserved by the pooling allocator
+    #[clap(long, default_value_t = 1000)]
+    instance_slots: u32,
+    /// Directory to cache compiled modules in, defaults to the user's cache
+    /// directory
+    #[clap(long, conflicts_with = "no_compile_cache")]
+    compile_cache_dir: Option<PathBuf>,
+    /// Disable caching compiled modules on disk
+    #[clap(long)]
+    no_compile_cache: bool,
 }
 
-pub async fn start() -> Result<()> {
-    let cli = Cli::parse();
+/// A module specific argument in the form `<module>=<value>`.
+#[derive(Clone, Debug)]
+struct ModuleArg<T> {
+    name: ModuleName,
+    value: T,
+}
+
+impl Cli {
+    /// Initializes logging, along with the trace exporter if enabled.
+    pub fn init_tracing(&self) -> Result<()> {
+        let tracer = match (&self.otlp_endpoint, &self.trace_file) {
+            (Some(endpoint), _) => Some(
+                opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(
+                        opentelemetry_otlp::new_exporter()
+                            .tonic()
+                            .with_endpoint(endpoint),
+                    )
+                    .with_trace_config(trace::config().with_resource(Resource::new([
+                        KeyValue::new("service.name", "thalo_runtime"),
+                    ])))
+                    .install_batch(opentelemetry::runtime::Tokio)?,
+            ),
+            (None, Some(path)) if path.as_os_str() == "-" => Some(
+                stdout::new_pipeline()
+                    .with_writer(io::stdout())
+                    .install_simple(),
+            ),
+            (None, Some(path)) => Some(
+                stdout::new_pipeline()
+                    .with_writer(File::create(path).context("failed to create trace file")?)
+                    .install_simple(),
+            ),
+            (None, None) => None,
+        };
+        if tracer.is_some() {
+            global::set_text_map_propagator(TraceContextPropagator::new());
+        }
+
+        tracing_subscriber::registry()
+            .with(
+                EnvFilter::builder()
+                    .with_default_directive("thalo_runtime=info".parse().unwrap())
+                    .from_env_lossy(),
+            )
+            .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::NEW | FmtSpan::CLOSE))
+            .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+            .init();
+
+        Ok(())
+    }
+}
+
+pub async fn start(cli: Cli) -> Result<()> {
+    if let Some(metrics_listen) = cli.metrics_listen {
+        PrometheusBuilder::new()
+            .with_http_listener(metrics_listen)
+            .install()
+            .context("failed to start metrics listener")?;
+        telemetry::describe_metrics();
+        info!("serving metrics on {metrics_listen}");
+    }
 
     let message_store = MessageStore::connect(&cli.database_url).await?;
     let registry_store = Registry::connect(&cli.database_url).await?;
-    let runtime = Runtime::new(message_store, registry_store);
+    let default_config = Config::default();
+    let default_module_limits = ModuleLimits {
+        execution_budget: Duration::from_millis(cli.execution_budget_ms),
+        max_memory_pages: cli.max_memory_pages,
+        max_table_elements: cli.max_table_elements,
+        max_instances: cli.max_instances,
+        max_tables: cli.max_tables,
+        max_memories: cli.max_memories,
+        instance_pool_size: cli.instance_pool_size,
+    };
+    let mut module_limits: HashMap<_, ModuleLimits> = HashMap::new();
+    for arg in cli.module_execution_budget_ms {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .execution_budget = Duration::from_millis(arg.value);
+    }
+    for arg in cli.module_max_memory_pages {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .max_memory_pages = arg.value;
+    }
+    for arg in cli.module_max_table_elements {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .max_table_elements = arg.value;
+    }
+    for arg in cli.module_max_instances {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .max_instances = arg.value;
+    }
+    for arg in cli.module_max_tables {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .max_tables = arg.value;
+    }
+    for arg in cli.module_max_memories {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .max_memories = arg.value;
+    }
+    for arg in cli.module_instance_pool_size {
+        module_limits
+            .entry(arg.name)
+            .or_insert_with(|| default_module_limits.clone())
+            .instance_pool_size = arg.value;
+    }
+    let config = Config {
+        snapshot_interval: cli.snapshot_interval,
+        handler_capacity: cli.handler_capacity,
+        router_shards: cli.router_shards.unwrap_or(default_config.router_shards),
+        conflict_retry: RetryPolicy {
+            max_retries: cli.conflict_max_retries,
+            ..default_config.conflict_retry
+        },
+        shutdown_timeout: Duration::from_secs(cli.shutdown_timeout_secs),
+        consumer_group: cli
+            .consumer_group_member
+            .zip(cli.consumer_group_size)
+            .map(|(member, size)| ConsumerGroup::new(member, size))
+            .transpose()?,
+        module_versions: cli
+            .module_version
+            .into_iter()
+            .map(|arg| (arg.name, arg.value))
+            .collect(),
+        instance_slots: cli.instance_slots,
+        compile_cache_dir: if cli.no_compile_cache {
+            None
+        } else {
+            cli.compile_cache_dir.or(default_config.compile_cache_dir)
+        },
+        default_module_limits,
+        module_limits,
+    };
+    let runtime = Runtime::new(message_store, registry_store, config);
     runtime.init().await?;
     runtime.start().await;
 
@@ -51,6 +303,55 @@ pub async fn start() -> Result<()> {
         cli.stateless_retry,
         cli.listen,
         runtime,
+        shutdown_signal(),
     )
     .await
 }
+
+/// Completes once SIGINT or SIGTERM is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("failed to listen for ctrl-c: {err}");
+            future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                error!("failed to listen for SIGTERM: {err}");
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("received SIGINT"),
+        _ = terminate => info!("received SIGTERM"),
+    }
+}
+
+impl<T> str::FromStr for ModuleArg<T>
+where
+    T: str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <module>=<value>"))?;
+        Ok(ModuleArg {
+            name: name.parse()?,
+            value: value.parse()?,
+        })
+    }
+}
@@ -1,45 +1,136 @@
 mod handler;
 
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
+use lru::LruCache;
 use message_db::database::MessageStore;
 use message_db::stream_name::{Category, StreamName, ID};
-use semver::VersionReq;
+use semver::Version;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thalo::Context;
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::oneshot;
+use tracing::trace;
+use uuid::Uuid;
 
-use self::handler::CommandHandler;
-use crate::module::{ExecuteResult, ModuleName};
+use self::handler::{CommandHandler, HandlerMsg, Queued};
+use crate::module::{ExecuteResult, ModuleID, ModuleName};
 use crate::runtime::Runtime;
 
+/// Command metadata property requesting a module version range to handle the
+/// command.
+pub const MODULE_VERSION_PROPERTY: &str = "module_version";
+
+/// Routes commands to entity handlers.
+///
+/// Stream names are hashed onto a fixed number of shards, each running in its
+/// own task. Commands for a single entity are always routed to the same shard
+/// and handler, keeping them strictly ordered, while unrelated entities are
+/// handled in parallel.
 #[derive(Clone, Debug)]
 pub struct CommandRouter {
-    tx: Sender<ExecuteMsg>,
+    shards: Arc<[Sender<RouterMsg>]>,
+}
+
+/// A command could not be saved due to concurrent writes to the entity stream,
+/// even after retrying against the refreshed state.
+#[derive(Debug, Error)]
+#[error("version conflict on stream '{stream_name}' after {attempts} attempts")]
+pub struct ConflictError {
+    pub stream_name: StreamName,
+    pub attempts: u32,
 }
 
-struct ExecuteMsg {
-    tx: oneshot::Sender<Result<ExecuteResult>>,
+/// A redelivered command was not handled again, as the entity already
+/// handled it.
+#[derive(Debug, Error)]
+#[error("command {0} was already handled")]
+pub struct CommandAlreadyHandled(pub Uuid);
+
+/// A command executed directly was not handled, as earlier commands of its
+/// stream are still to be handled by the command subscription, which handles
+/// it after them.
+#[derive(Debug, Error)]
+#[error("command {0} is behind unhandled commands of its stream")]
+pub struct CommandNotNext(pub Uuid);
+
+/// Error context identifying the module version which failed to handle a
+/// command.
+#[derive(Clone, Debug)]
+pub struct CommandFailed {
+    pub module_id: ModuleID,
+}
+
+/// The state of an entity at a position of its stream.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntityState {
+    pub module_version: Version,
+    /// Position of the last event applied to the state, or -1 if none were.
+    pub position: i64,
+    pub state: Value,
+}
+
+enum RouterMsg {
+    Route(RouteMsg),
+    Upgrade(ModuleName),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// A message for the handler of an entity stream, started if not running.
+struct RouteMsg {
     runtime: Runtime,
     message_store: MessageStore,
     name: ModuleName,
-    id: String,
-    ctx: Context,
-    command: String,
-    payload: Value,
+    stream_name: StreamName,
+    msg: HandlerMsg,
+    routed: oneshot::Sender<Routed>,
+}
+
+/// The result of routing a message to a handler.
+enum Routed {
+    /// The message was queued, or rejected if the handler stopped.
+    Sent,
+    /// The handler's queue is full, so the message is handed back to the
+    /// sender to wait for room, rather than blocking the router.
+    Full(CommandHandler, Queued),
 }
 
 impl CommandRouter {
-    pub fn start() -> Self {
-        let (tx, rx) = mpsc::channel(1024);
-        tokio::spawn(command_router(rx));
-        CommandRouter { tx }
+    /// Starts the command router with `shards` router tasks, keeping at most
+    /// `capacity` entity handlers alive at once.
+    pub fn start(shards: NonZeroUsize, capacity: NonZeroUsize) -> Self {
+        let shard_capacity =
+            NonZeroUsize::new((capacity.get() + shards.get() - 1) / shards.get()).unwrap();
+        let shards = (0..shards.get())
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(1024);
+                tokio::spawn(command_router(rx, shard_capacity));
+                tx
+            })
+            .collect();
+
+        CommandRouter { shards }
     }
 
+    /// Queues a command with its entity's handler, returning a receiver of
+    /// the command's result once queued.
+    ///
+    /// Waits for room if the entity's queue is full, so commands submitted one
+    /// after another are handled in order.
+    ///
+    /// A `direct` command from the command stream is only handled if every
+    /// earlier command of the stream was, failing with [`CommandNotNext`]
+    /// otherwise.
     #[allow(clippy::too_many_arguments)]
-    pub async fn execute(
+    pub async fn submit(
         &self,
         runtime: Runtime,
         message_store: MessageStore,
@@ -48,86 +139,287 @@ impl CommandRouter {
         ctx: Context,
         command: String,
         payload: Value,
-    ) -> Result<ExecuteResult> {
+        direct: bool,
+    ) -> Result<oneshot::Receiver<Result<ExecuteResult>>> {
+        let (tx, rx) = oneshot::channel();
+        let msg = HandlerMsg::Execute(handler::ExecuteMsg {
+            tx,
+            ctx,
+            command,
+            payload,
+            direct,
+        });
+        self.route(runtime, message_store, name, id, msg)
+            .await
+            .map_err(|err| anyhow!("failed to send execute msg: {err}"))?;
+
+        Ok(rx)
+    }
+
+    /// Returns the current state of an entity, or its state at a stream
+    /// position.
+    ///
+    /// The entity's handler is started if it isn't running, and catches up
+    /// with the entity stream before returning its state.
+    pub async fn get_state(
+        &self,
+        runtime: Runtime,
+        message_store: MessageStore,
+        name: ModuleName,
+        id: String,
+        at_position: Option<i64>,
+    ) -> Result<EntityState> {
         let (tx, rx) = oneshot::channel();
-        self.do_execute(tx, runtime, message_store, name, id, ctx, command, payload)
-            .await?;
+        let msg = HandlerMsg::GetState(handler::GetStateMsg { tx, at_position });
+        self.route(runtime, message_store, name, id, msg)
+            .await
+            .map_err(|err| anyhow!("failed to send get state msg: {err}"))?;
         rx.await?
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub async fn do_execute(
+    async fn route(
         &self,
-        tx: oneshot::Sender<Result<ExecuteResult>>,
         runtime: Runtime,
         message_store: MessageStore,
         name: ModuleName,
         id: String,
-        ctx: Context,
-        command: String,
-        payload: Value,
+        msg: HandlerMsg,
     ) -> Result<()> {
-        self.tx
-            .send(ExecuteMsg {
-                tx,
+        let category: Category = Category::normalize(&name).parse()?;
+        let stream_name = StreamName {
+            category,
+            id: Some(ID::new(id)?),
+        };
+
+        let (routed_tx, routed_rx) = oneshot::channel();
+        self.shard(&stream_name)
+            .send(RouterMsg::Route(RouteMsg {
                 runtime,
                 message_store,
                 name,
-                id,
-                ctx,
-                command,
-                payload,
-            })
+                stream_name,
+                msg,
+                routed: routed_tx,
+            }))
             .await
-            .map_err(|err| anyhow!("failed to send execute msg: {err}"))
+            .map_err(|err| anyhow!("{err}"))?;
+
+        if let Routed::Full(handler, queued) = routed_rx.await? {
+            if let Err(msg) = handler.send(queued).await {
+                msg.reject("command handler stopped");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rolls running handlers of a module onto the latest matching module
+    /// version.
+    pub async fn upgrade(&self, name: ModuleName) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard
+                .send(RouterMsg::Upgrade(name.clone()))
+                .await
+                .map_err(|err| anyhow!("failed to send upgrade msg: {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops the router, waiting for all queued commands to be handled.
+    ///
+    /// Commands submitted after shutdown fail to be routed.
+    pub async fn shutdown(&self) {
+        let mut stopped = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            let (tx, rx) = oneshot::channel();
+            if shard.send(RouterMsg::Shutdown(tx)).await.is_ok() {
+                stopped.push(rx);
+            }
+        }
+
+        for rx in stopped {
+            let _ = rx.await;
+        }
+    }
+
+    fn shard(&self, stream_name: &StreamName) -> &Sender<RouterMsg> {
+        let mut hasher = DefaultHasher::new();
+        stream_name.hash(&mut hasher);
+        let index = hasher.finish() % self.shards.len() as u64;
+        &self.shards[index as usize]
     }
 }
 
-async fn command_router(mut rx: Receiver<ExecuteMsg>) {
-    let mut streams: HashMap<Category, CommandHandler> = HashMap::new();
-
-    while let Some(req) = rx.recv().await {
-        let stream_name = match Category::normalize(&req.name)
-            .parse()
-            .and_then(|category: Category| Ok((category, ID::new(req.id)?)))
-        {
-            Ok((category, id)) => StreamName {
-                category,
-                id: Some(id),
-            },
-            Err(err) => {
-                let _ = req.tx.send(Err(err.into()));
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "module '{}' version {} failed to handle command",
+            self.module_id.name, self.module_id.version
+        )
+    }
+}
+
+async fn command_router(mut rx: Receiver<RouterMsg>, capacity: NonZeroUsize) {
+    // Idle entities are evicted when the capacity is reached. Dropping an idle
+    // handler closes its channel, stopping it.
+    let mut streams: LruCache<StreamName, CommandHandler> = LruCache::unbounded();
+    // Each handler holds a clone of the sender, so the receiver completes once
+    // all handlers have stopped.
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+
+    while let Some(msg) = rx.recv().await {
+        let req = match msg {
+            RouterMsg::Route(req) => req,
+            RouterMsg::Upgrade(name) => {
+                let entity_name = Category::normalize(&name);
+                let handlers: Vec<_> = streams
+                    .iter()
+                    .filter(|(stream_name, _)| stream_name.category.entity_name == entity_name)
+                    .map(|(_, handler)| handler.clone())
+                    .collect();
+                for handler in handlers {
+                    if let Err(TrySendError::Full(queued)) = handler.try_send(HandlerMsg::Upgrade) {
+                        // Queued behind the handler's commands without blocking the router
+                        tokio::spawn(async move {
+                            let _ = handler.send(queued).await;
+                        });
+                    }
+                }
                 continue;
             }
+            RouterMsg::Shutdown(done) => {
+                streams.clear();
+                drop(drain_tx);
+                let _ = drain_rx.recv().await;
+                trace!("command router stopped");
+                let _ = done.send(());
+                return;
+            }
         };
 
-        match streams.get(&stream_name.category) {
-            Some(handler) => {
-                let _ = handler
-                    .do_execute(req.tx, req.ctx, req.command, req.payload)
-                    .await;
-            }
-            None => {
-                match CommandHandler::start(
-                    &req.runtime,
-                    req.message_store,
-                    &req.name,
-                    stream_name.clone(),
-                    &VersionReq::STAR,
-                )
-                .await
-                {
-                    Ok(handler) => {
-                        let _ = handler
-                            .do_execute(req.tx, req.ctx, req.command, req.payload)
-                            .await;
-                        streams.insert(stream_name.category, handler);
-                    }
-                    Err(err) => {
-                        let _ = req.tx.send(Err(anyhow!("failed to start handler: {err}")));
-                    }
-                };
+        let RouteMsg {
+            runtime,
+            message_store,
+            name,
+            stream_name,
+            msg,
+            routed,
+        } = req;
+
+        // Handlers are never awaited, so a busy entity doesn't hold up the other
+        // entities of the shard
+        let msg = match streams.get(&stream_name) {
+            Some(handler) => match handler.try_send(msg) {
+                Ok(()) => {
+                    let _ = routed.send(Routed::Sent);
+                    continue;
+                }
+                Err(TrySendError::Full(queued)) => {
+                    let _ = routed.send(Routed::Full(handler.clone(), queued));
+                    continue;
+                }
+                Err(TrySendError::Closed(queued)) => {
+                    // The handler stopped, so it is restarted below
+                    streams.pop(&stream_name);
+                    queued.into_msg()
+                }
+            },
+            None => msg,
+        };
+
+        // The handler loads its module and replays its stream in the background,
+        // queueing commands in the meantime so the router is never blocked.
+        let handler = CommandHandler::start(
+            runtime,
+            message_store,
+            name,
+            stream_name.clone(),
+            drain_tx.clone(),
+        );
+        let res = handler.try_send(msg);
+        let _ = routed.send(Routed::Sent);
+        if let Err(TrySendError::Full(queued) | TrySendError::Closed(queued)) = res {
+            queued.into_msg().reject("command handler stopped");
+            continue;
+        }
+        streams.put(stream_name, handler);
+        evict_idle(&mut streams, capacity);
+    }
+}
+
+/// Evicts the least recently used idle handlers until at most `capacity`
+/// handlers are alive.
+///
+/// Busy handlers are never evicted, so the capacity is exceeded while all
+/// handlers are busy.
+fn evict_idle(streams: &mut LruCache<StreamName, CommandHandler>, capacity: NonZeroUsize) {
+    while streams.len() > capacity.get() {
+        let idle = streams
+            .iter()
+            .rev()
+            .find(|(_, handler)| handler.is_idle())
+            .map(|(stream_name, _)| stream_name.clone());
+        match idle {
+            Some(stream_name) => {
+                streams.pop(&stream_name);
+                trace!(%stream_name, "evicted idle command handler");
             }
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_name(id: &str) -> StreamName {
+        format!("counter-{id}").parse().unwrap()
+    }
+
+    #[test]
+    fn evict_idle_keeps_handlers_in_flight() {
+        let capacity = NonZeroUsize::new(1).unwrap();
+        let mut streams = LruCache::unbounded();
+
+        let (busy, mut busy_rx) = CommandHandler::detached();
+        assert!(busy.try_send(HandlerMsg::Upgrade).is_ok());
+        // Received by the handler but still being handled
+        let in_flight = busy_rx.try_recv().unwrap();
+        let (queued, _queued_rx) = CommandHandler::detached();
+        assert!(queued.try_send(HandlerMsg::Upgrade).is_ok());
+        streams.put(stream_name("1"), busy);
+        streams.put(stream_name("2"), queued);
+
+        evict_idle(&mut streams, capacity);
+        assert_eq!(streams.len(), 2);
+
+        drop(in_flight);
+        evict_idle(&mut streams, capacity);
+        assert_eq!(streams.len(), 1);
+        assert!(!streams.contains(&stream_name("1")));
+        assert!(streams.contains(&stream_name("2")));
+    }
+
+    #[test]
+    fn evict_idle_evicts_least_recently_used_first() {
+        let capacity = NonZeroUsize::new(2).unwrap();
+        let mut streams = LruCache::unbounded();
+        let mut receivers = Vec::new();
+        for id in ["1", "2", "3", "4"] {
+            let (handler, rx) = CommandHandler::detached();
+            streams.put(stream_name(id), handler);
+            receivers.push(rx);
         }
+        streams.get(&stream_name("1"));
+        let busy = streams.get(&stream_name("3")).unwrap();
+        assert!(busy.try_send(HandlerMsg::Upgrade).is_ok());
+
+        evict_idle(&mut streams, capacity);
+        assert_eq!(streams.len(), 2);
+        assert!(streams.contains(&stream_name("1")));
+        assert!(streams.contains(&stream_name("3")));
     }
 }
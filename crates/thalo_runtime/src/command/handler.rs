@@ -1,169 +1,710 @@
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Context as AnyhowContext, Result};
-use futures::TryFutureExt;
 use message_db::database::{GetStreamMessagesOpts, MessageStore, WriteMessageOpts};
-use message_db::message::{MessageData, MetadataRef};
+use message_db::message::{GenericMessage, MessageData, MetadataRef};
 use message_db::stream_name::StreamName;
+use metrics::{decrement_gauge, increment_gauge};
 use semver::VersionReq;
 use serde_json::Value;
-use thalo::Context;
+use thalo::{
+    Context, ScheduleChange, CORRELATION_ID_PROPERTY, TRACEPARENT_PROPERTY, TRACESTATE_PROPERTY,
+};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn};
 
-use crate::module::{Event, EventRef, ExecuteResult, ModuleInstance, ModuleName};
-use crate::runtime::Runtime;
+use super::{
+    CommandAlreadyHandled, CommandFailed, CommandNotNext, ConflictError, EntityState,
+    MODULE_VERSION_PROPERTY,
+};
+use crate::config::RetryPolicy;
+use crate::module::{self, Event, ExecuteResult, ModuleID, ModuleInstance, ModuleName};
+use crate::runtime::{self, Runtime};
+use crate::snapshot::{Snapshot, SnapshotStore};
+use crate::telemetry::COMMAND_HANDLERS;
 
+/// Number of events loaded per query when replaying a stream.
+const REPLAY_BATCH_SIZE: i64 = 1_000;
+/// Postgres error code of exceptions raised by PL/pgSQL functions.
+const RAISE_EXCEPTION_CODE: &str = "P0001";
+
+#[derive(Clone)]
 pub struct CommandHandler {
-    tx: Sender<ExecuteMsg>,
+    tx: Sender<Queued>,
+    /// Number of messages queued or being handled.
+    pending: Arc<AtomicUsize>,
 }
 
-struct ExecuteMsg {
-    tx: oneshot::Sender<Result<ExecuteResult>>,
-    ctx: Context,
-    command: String,
-    payload: Value,
+struct Handler {
+    runtime: Runtime,
+    module_name: ModuleName,
+    message_store: MessageStore,
+    snapshot_store: SnapshotStore,
+    instance: ModuleInstance,
+    stream_name: StreamName,
+    version: i64,
+    snapshot_position: i64,
+    /// The latest snapshot save running in the background, awaited by the next
+    /// one so snapshots are saved in order.
+    snapshot_save: Option<JoinHandle<()>>,
+    retry_policy: RetryPolicy,
+    command_stream_name: StreamName,
+    /// Position in the command stream of the last handled command, or -1 if
+    /// none were.
+    ///
+    /// Persisted through the causation metadata of events, snapshots and
+    /// recorded outcomes, so redelivered commands are never handled twice.
+    last_command_position: i64,
+}
+
+pub(super) enum HandlerMsg {
+    Execute(ExecuteMsg),
+    GetState(GetStateMsg),
+    /// Switches to the latest module version matching the module's pinned
+    /// version requirement.
+    ///
+    /// Commands queued before the upgrade are still handled by the previous
+    /// module version.
+    Upgrade,
+}
+
+/// A message queued for a handler, counted as pending until dropped once
+/// handled.
+pub(super) struct Queued {
+    msg: HandlerMsg,
+    _pending: Pending,
+}
+
+struct Pending(Arc<AtomicUsize>);
+
+pub(super) struct ExecuteMsg {
+    pub(super) tx: oneshot::Sender<Result<ExecuteResult>>,
+    pub(super) ctx: Context,
+    pub(super) command: String,
+    pub(super) payload: Value,
+    /// Whether the command is executed directly on submission, rather than
+    /// delivered by the command subscription.
+    pub(super) direct: bool,
+}
+
+pub(super) struct GetStateMsg {
+    pub(super) tx: oneshot::Sender<Result<EntityState>>,
+    pub(super) at_position: Option<i64>,
 }
 
 impl CommandHandler {
-    pub async fn start(
-        runtime: &Runtime,
+    /// Spawns a handler for an entity stream.
+    ///
+    /// The module is loaded and the stream replayed in the background, with
+    /// commands being queued until the handler is ready.
+    pub fn start(
+        runtime: Runtime,
         message_store: MessageStore,
-        module_name: &ModuleName,
+        module_name: ModuleName,
         stream_name: StreamName,
-        version: &VersionReq,
-    ) -> Result<Self> {
-        let module_fut =
-            runtime
-                .load_module(module_name, version)
-                .and_then(|(_module_id, module)| {
-                    let id = stream_name.id.as_ref().unwrap().to_string();
-                    async move {
-                        let instance = module.init(id).await?;
-                        Ok(instance)
-                    }
-                });
-
-        let stream_name_string = stream_name.to_string();
-        let opts = GetStreamMessagesOpts::default();
-        let (module_res, events_res) = tokio::join!(
-            module_fut,
-            MessageStore::get_stream_messages::<MessageData, _>(
-                &message_store,
-                &stream_name_string,
-                &opts
+        drain_guard: Sender<()>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            // Held until the handler stops, letting the router wait for queued
+            // commands to drain on shutdown
+            let _drain_guard = drain_guard;
+            increment_gauge!(COMMAND_HANDLERS, 1.0);
+            let version_req = runtime.version_req(&module_name).await;
+            match Handler::load(
+                runtime,
+                message_store,
+                module_name,
+                stream_name.clone(),
+                &version_req,
             )
-        );
-        let mut instance = module_res?;
-
-        // Apply events
-        let events = events_res?;
-        let version = events.last().map(|event| event.position).unwrap_or(-1);
-        let events: Vec<_> = events
-            .into_iter()
-            .map(|mut event| {
-                let ctx_json = event
-                    .metadata
-                    .properties
-                    .remove("ctx")
-                    .ok_or_else(|| anyhow!("missing ctx in event metadata"))?;
-                let ctx = serde_json::from_value(ctx_json)
-                    .context("failed to deserialize ctx in metadata")?;
-                Ok((ctx, event.msg_type, serde_json::to_vec(&event.data)?))
-            })
-            .collect::<Result<_>>()?;
-
-        let event_refs: Vec<_> = events
-            .iter()
-            .map(|(ctx, event_type, payload)| EventRef {
-                ctx,
-                event_type,
-                payload,
-            })
-            .collect();
+            .await
+            {
+                Ok(handler) => handler.run(rx).await,
+                Err(err) => {
+                    error!(%stream_name, "failed to start handler: {err}");
+                    reject_queued(rx, &format!("failed to start handler: {err}")).await;
+                }
+            }
+            decrement_gauge!(COMMAND_HANDLERS, 1.0);
+        });
+
+        CommandHandler {
+            tx,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Queues a message without waiting, returning it back if the handler's
+    /// queue is full or the handler has stopped.
+    pub(super) fn try_send(&self, msg: HandlerMsg) -> Result<(), TrySendError<Queued>> {
+        self.tx.try_send(self.queued(msg))
+    }
+
+    /// Waits for room to queue a message returned by [`try_send`], returning
+    /// it back if the handler has stopped.
+    ///
+    /// [`try_send`]: CommandHandler::try_send
+    pub(super) async fn send(&self, queued: Queued) -> Result<(), HandlerMsg> {
+        self.tx.send(queued).await.map_err(|err| err.0.msg)
+    }
+
+    /// Returns whether the handler has no queued or in-progress messages.
+    ///
+    /// Only idle handlers may be evicted, as a handler started for the same
+    /// stream would otherwise run concurrently with the evicted one.
+    pub(super) fn is_idle(&self) -> bool {
+        self.pending.load(Ordering::Acquire) == 0
+    }
 
-        instance.apply(&event_refs).await?;
+    fn queued(&self, msg: HandlerMsg) -> Queued {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        Queued {
+            msg,
+            _pending: Pending(Arc::clone(&self.pending)),
+        }
+    }
+}
 
+#[cfg(test)]
+impl CommandHandler {
+    /// Creates a handler without a running task, its messages being received
+    /// by the returned queue instead.
+    pub(super) fn detached() -> (Self, Receiver<Queued>) {
         let (tx, rx) = mpsc::channel(64);
-        tokio::spawn(command_handler(
-            rx,
+        let handler = CommandHandler {
+            tx,
+            pending: Arc::new(AtomicUsize::new(0)),
+        };
+
+        (handler, rx)
+    }
+}
+
+impl Queued {
+    pub(super) fn into_msg(self) -> HandlerMsg {
+        self.msg
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Handler {
+    async fn load(
+        runtime: Runtime,
+        message_store: MessageStore,
+        module_name: ModuleName,
+        stream_name: StreamName,
+        version_req: &VersionReq,
+    ) -> Result<Self> {
+        let (module_id, module) = runtime.load_module(&module_name, version_req).await?;
+        let snapshot_store = runtime.snapshot_store().clone();
+        let id = stream_name.id.as_ref().unwrap().to_string();
+        let command_stream_name = runtime::command_stream_name(&module_name, &id)?;
+
+        // Ignored commands leave no events, so are only found in recorded outcomes
+        let mut last_command_position = runtime
+            .outcome_store()
+            .last_command_position(&command_stream_name)
+            .await?
+            .unwrap_or(-1);
+
+        // Start from the latest compatible snapshot if there is one
+        let snapshot = snapshot_store
+            .load(&stream_name, &module_id.version)
+            .await?;
+        let (instance, snapshot_position) = match snapshot {
+            Some(snapshot) => {
+                let state = serde_json::to_vec(&snapshot.state)?;
+                if let Some(position) = snapshot.command_position {
+                    last_command_position = last_command_position.max(position);
+                }
+                (module.restore(state), snapshot.position)
+            }
+            None => {
+                let res = module.init(id).await;
+                (check_trap(&runtime, &module_id, res).await?, -1)
+            }
+        };
+
+        let retry_policy = runtime.config().conflict_retry.clone();
+        let mut handler = Handler {
+            runtime,
+            module_name,
             message_store,
+            snapshot_store,
             instance,
             stream_name,
-            version,
-        ));
+            version: snapshot_position,
+            snapshot_position,
+            snapshot_save: None,
+            retry_policy,
+            command_stream_name,
+            last_command_position,
+        };
+        let res = handler.replay().await;
+        check_trap(&handler.runtime, &module_id, res).await?;
+        handler.snapshot_if_needed();
+
+        Ok(handler)
+    }
+
+    async fn run(mut self, mut rx: Receiver<Queued>) {
+        while let Some(Queued { msg, _pending }) = rx.recv().await {
+            match msg {
+                HandlerMsg::Execute(req) => {
+                    let res = self
+                        .handle_command(req.ctx, req.command, req.payload, req.direct)
+                        .await;
+                    let res = check_trap(&self.runtime, self.instance.id(), res).await;
+                    let trapped = matches!(&res, Err(err) if module::is_trap(err));
+                    let res = res.map_err(|err| {
+                        err.context(CommandFailed {
+                            module_id: self.instance.id().clone(),
+                        })
+                    });
+                    let _ = req.tx.send(res);
+                    if trapped {
+                        // The entity state can't be trusted after a trap, so the handler is
+                        // restarted from the stream on the next command
+                        warn!(stream_name = %self.stream_name, "discarding command handler");
+                        reject_queued(rx, "command handler was discarded").await;
+                        return;
+                    }
+                }
+                HandlerMsg::GetState(req) => {
+                    let res = self.get_state(req.at_position).await;
+                    let res = check_trap(&self.runtime, self.instance.id(), res).await;
+                    let trapped = matches!(&res, Err(err) if module::is_trap(err));
+                    let _ = req.tx.send(res);
+                    if trapped {
+                        warn!(stream_name = %self.stream_name, "discarding command handler");
+                        reject_queued(rx, "command handler was discarded").await;
+                        return;
+                    }
+                }
+                HandlerMsg::Upgrade => {
+                    if let Err(err) = self.upgrade().await {
+                        error!(stream_name = %self.stream_name, "failed to upgrade handler: {err}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Switches to the latest module version matching the module's pinned
+    /// version requirement.
+    async fn upgrade(&mut self) -> Result<()> {
+        let version_req = self.runtime.version_req(&self.module_name).await;
+        self.switch_version(&version_req).await
+    }
+
+    /// Ensures the module version handling the command matches the version
+    /// requested in the command metadata, or the module's pinned version
+    /// requirement otherwise.
+    async fn ensure_version(&mut self, ctx: &Context) -> Result<()> {
+        let version_req = match ctx.metadata.properties.get(MODULE_VERSION_PROPERTY) {
+            Some(version_req) => version_req
+                .as_str()
+                .ok_or_else(|| anyhow!("module version in command metadata must be a string"))?
+                .parse()
+                .context("invalid module version in command metadata")?,
+            None => self.runtime.version_req(&self.module_name).await,
+        };
+        if version_req.matches(&self.instance.id().version) {
+            return Ok(());
+        }
 
-        Ok(CommandHandler { tx })
+        self.switch_version(&version_req).await
     }
 
-    // pub async fn execute(&self, command: String, payload: Vec<u8>) ->
-    // Result<ExecuteResult> {     let (tx, rx) = oneshot::channel();
-    //     self.do_execute(tx, command, payload).await?;
-    //     rx.await?
-    // }
+    /// Rebuilds the handler's state from events using the latest module
+    /// version matching the version requirement.
+    async fn switch_version(&mut self, version_req: &VersionReq) -> Result<()> {
+        let (module_id, _) = self
+            .runtime
+            .load_module(&self.module_name, version_req)
+            .await?;
+        if &module_id == self.instance.id() {
+            return Ok(());
+        }
+
+        let handler = Handler::load(
+            self.runtime.clone(),
+            self.message_store.clone(),
+            self.module_name.clone(),
+            self.stream_name.clone(),
+            version_req,
+        )
+        .await?;
+        info!(
+            stream_name = %self.stream_name,
+            from = %self.instance.id().version,
+            to = %module_id.version,
+            "switched module version"
+        );
+        *self = handler;
+
+        Ok(())
+    }
 
-    pub async fn do_execute(
-        &self,
-        tx: oneshot::Sender<Result<ExecuteResult>>,
+    async fn handle_command(
+        &mut self,
         ctx: Context,
         command: String,
         payload: Value,
-    ) -> Result<()> {
-        self.tx
-            .send(ExecuteMsg {
-                tx,
+        direct: bool,
+    ) -> Result<ExecuteResult> {
+        // Commands may be delivered more than once, such as when the subscription
+        // restarts before recording its position. An entity's commands are handled
+        // in the order of its command stream, so earlier positions were already
+        // handled.
+        let from_command_stream = ctx.stream_name == self.command_stream_name;
+        if from_command_stream && ctx.position <= self.last_command_position {
+            trace!(command_id = %ctx.id, "command already handled");
+            return Err(CommandAlreadyHandled(ctx.id).into());
+        }
+        // Handling a command directly ahead of earlier ones would mark those as
+        // handled, so it's left to the subscription
+        if direct && from_command_stream && ctx.position != self.last_command_position + 1 {
+            trace!(command_id = %ctx.id, "earlier commands not handled yet");
+            return Err(CommandNotNext(ctx.id).into());
+        }
+
+        let result = self
+            .execute_command(&ctx, command, payload, from_command_stream)
+            .await;
+        if from_command_stream && result.is_err() {
+            // Failed commands are dead lettered rather than handled again
+            self.last_command_position = self.last_command_position.max(ctx.position);
+        }
+
+        result
+    }
+
+    async fn execute_command(
+        &mut self,
+        ctx: &Context,
+        command: String,
+        payload: Value,
+        from_command_stream: bool,
+    ) -> Result<ExecuteResult> {
+        self.ensure_version(ctx).await?;
+
+        let command_payload = serde_json::to_vec(&payload)?;
+        let mut attempts = 0;
+        loop {
+            let (result, schedules) = self
+                .instance
+                .handle(ctx, &command, &command_payload)
+                .await?;
+            let events = result.events();
+            if events.is_empty() {
+                if from_command_stream {
+                    self.last_command_position = ctx.position;
+                }
+                self.save_schedule_changes(ctx, &schedules).await;
+                return Ok(result);
+            }
+
+            // Events are only applied once saved, so the state stays valid if another
+            // writer appended to the stream in the meantime.
+            match save_events(
+                &self.message_store,
+                &self.stream_name,
+                self.version,
                 ctx,
-                command,
-                payload,
-            })
+                events,
+            )
             .await
-            .map_err(|err| anyhow!("failed to send execute msg: {err}"))
+            {
+                Ok(version) => {
+                    let event_refs: Vec<_> = events.iter().map(Event::as_ref).collect();
+                    self.instance.apply(&event_refs).await?;
+                    self.version = version;
+                    if from_command_stream {
+                        self.last_command_position = ctx.position;
+                    }
+                    self.snapshot_if_needed();
+                    self.save_schedule_changes(ctx, &schedules).await;
+                    return Ok(result);
+                }
+                Err(err) if is_wrong_expected_version(&err) => {
+                    attempts += 1;
+                    if attempts > self.retry_policy.max_retries {
+                        return Err(ConflictError {
+                            stream_name: self.stream_name.clone(),
+                            attempts,
+                        }
+                        .into());
+                    }
+
+                    let backoff = self.retry_policy.backoff(attempts - 1);
+                    warn!(
+                        stream_name = %self.stream_name,
+                        attempts,
+                        ?backoff,
+                        "version conflict, retrying command"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    self.replay().await?;
+                    if from_command_stream && ctx.position <= self.last_command_position {
+                        // Handled by another writer in the meantime
+                        return Err(CommandAlreadyHandled(ctx.id).into());
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Returns the entity's current state, or its state at a stream position.
+    ///
+    /// Events written since the handler last read the stream are applied
+    /// first, such as those written by other nodes.
+    async fn get_state(&mut self, at_position: Option<i64>) -> Result<EntityState> {
+        self.replay().await?;
+        let (state, position) = match at_position {
+            Some(position) if position < self.version => {
+                let position = position.max(-1);
+                (self.state_at(position).await?, position)
+            }
+            _ => (self.instance.state().to_vec(), self.version),
+        };
+
+        Ok(EntityState {
+            module_version: self.instance.id().version.clone(),
+            position,
+            state: serde_json::from_slice(&state).context("state is not valid json")?,
+        })
+    }
+
+    /// Rebuilds the state as of a stream position on a separate instance,
+    /// leaving the handler's state untouched.
+    ///
+    /// Starts from the latest snapshot if it was taken at or before the
+    /// position.
+    async fn state_at(&self, position: i64) -> Result<Vec<u8>> {
+        let module = self.instance.module();
+        let snapshot = self
+            .snapshot_store
+            .load(&self.stream_name, &self.instance.id().version)
+            .await?
+            .filter(|snapshot| snapshot.position <= position);
+        let (mut instance, mut version) = match snapshot {
+            Some(snapshot) => {
+                let state = serde_json::to_vec(&snapshot.state)?;
+                (module.restore(state), snapshot.position)
+            }
+            None => {
+                let id = self.stream_name.id.as_ref().unwrap().to_string();
+                (module.init(id).await?, -1)
+            }
+        };
+
+        let stream_name = self.stream_name.to_string();
+        while version < position {
+            let opts = GetStreamMessagesOpts::builder()
+                .position(version + 1)
+                .batch_size(REPLAY_BATCH_SIZE.min(position - version))
+                .build();
+            let messages = MessageStore::get_stream_messages::<MessageData, _>(
+                &self.message_store,
+                &stream_name,
+                &opts,
+            )
+            .await?;
+            let last_position = match messages.last() {
+                Some(message) => message.position,
+                None => break,
+            };
+
+            let events: Vec<_> = messages
+                .into_iter()
+                .map(event_from_message)
+                .collect::<Result<_>>()?;
+            let event_refs: Vec<_> = events.iter().map(Event::as_ref).collect();
+            instance.apply(&event_refs).await?;
+            version = last_position;
+        }
+
+        Ok(instance.state().to_vec())
+    }
+
+    /// Saves the changes to scheduled commands of an accepted command.
+    ///
+    /// The command's events are already saved at this point, so failures are
+    /// logged rather than failing the command.
+    async fn save_schedule_changes(&self, ctx: &Context, schedules: &[ScheduleChange]) {
+        if schedules.is_empty() {
+            return;
+        }
+        if let Err(err) = self.runtime.save_schedule_changes(ctx, schedules).await {
+            error!(command_id = %ctx.id, "failed to save scheduled commands: {err}");
+        }
+    }
+
+    /// Applies all events after the current version, in batches.
+    async fn replay(&mut self) -> Result<()> {
+        let stream_name = self.stream_name.to_string();
+        loop {
+            let opts = GetStreamMessagesOpts::builder()
+                .position(self.version + 1)
+                .batch_size(REPLAY_BATCH_SIZE)
+                .build();
+            let messages = MessageStore::get_stream_messages::<MessageData, _>(
+                &self.message_store,
+                &stream_name,
+                &opts,
+            )
+            .await?;
+            let last_position = match messages.last() {
+                Some(message) => message.position,
+                None => break,
+            };
+            let batch_len = messages.len();
+
+            // Events written by the handler record the command which caused them
+            for message in &messages {
+                let metadata = &message.metadata;
+                if metadata.causation_message_stream_name.as_ref()
+                    == Some(&self.command_stream_name)
+                {
+                    if let Some(position) = metadata.causation_message_position {
+                        self.last_command_position = self.last_command_position.max(position);
+                    }
+                }
+            }
+
+            let events: Vec<_> = messages
+                .into_iter()
+                .map(event_from_message)
+                .collect::<Result<_>>()?;
+            let event_refs: Vec<_> = events.iter().map(Event::as_ref).collect();
+            self.instance.apply(&event_refs).await?;
+            self.version = last_position;
+
+            if (batch_len as i64) < REPLAY_BATCH_SIZE {
+                break;
+            }
+        }
+
+        trace!(stream_name = %self.stream_name, version = self.version, "replayed stream");
+
+        Ok(())
+    }
+
+    /// Saves a snapshot in the background if enough events have been applied
+    /// since the last one.
+    fn snapshot_if_needed(&mut self) {
+        if !self
+            .snapshot_store
+            .should_snapshot(self.snapshot_position, self.version)
+        {
+            return;
+        }
+
+        let state = match serde_json::from_slice(self.instance.state()) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!(stream_name = %self.stream_name, "state is not valid json, skipping snapshot: {err}");
+                return;
+            }
+        };
+        let snapshot = Snapshot {
+            module_version: self.instance.id().version.clone(),
+            position: self.version,
+            command_position: Some(self.last_command_position),
+            state,
+        };
+        self.snapshot_position = self.version;
+
+        let snapshot_store = self.snapshot_store.clone();
+        let stream_name = self.stream_name.clone();
+        let previous = self.snapshot_save.take();
+        self.snapshot_save = Some(tokio::spawn(async move {
+            // An older snapshot saved after this one would be loaded instead
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            if let Err(err) = snapshot_store.save(&stream_name, &snapshot).await {
+                warn!(%stream_name, "{err}");
+            }
+        }));
     }
 }
 
-async fn command_handler(
-    mut rx: Receiver<ExecuteMsg>,
-    message_store: MessageStore,
-    mut instance: ModuleInstance,
-    stream_name: StreamName,
-    mut version: i64,
-) {
-    while let Some(req) = rx.recv().await {
-        let res = handle_command(
-            &message_store,
-            &mut instance,
-            &stream_name,
-            &mut version,
-            req.ctx,
-            req.command,
-            req.payload,
-        )
-        .await;
-        let _ = req.tx.send(res);
+/// Records the guest trapping with the runtime.
+async fn check_trap<T>(runtime: &Runtime, module_id: &ModuleID, res: Result<T>) -> Result<T> {
+    if let Err(err) = &res {
+        if module::is_trap(err) {
+            runtime.record_trap(module_id, err).await;
+        }
     }
+
+    res
 }
 
-async fn handle_command(
-    message_store: &MessageStore,
-    instance: &mut ModuleInstance,
-    stream_name: &StreamName,
-    version: &mut i64,
-    ctx: Context,
-    command: String,
-    payload: Value,
-) -> Result<ExecuteResult> {
-    let command_payload = serde_json::to_vec(&payload)?;
-    let result = instance
-        .handle_and_apply(&ctx, &command, &command_payload)
-        .await?;
+/// Stops receiving messages, failing any queued commands.
+async fn reject_queued(mut rx: Receiver<Queued>, reason: &str) {
+    rx.close();
+    while let Some(queued) = rx.recv().await {
+        queued.msg.reject(reason);
+    }
+}
 
-    if let ExecuteResult::Events(events) = &result {
-        if !events.is_empty() {
-            *version = save_events(message_store, stream_name, *version, &ctx, events).await?;
+impl HandlerMsg {
+    /// Fails the message's request.
+    pub(super) fn reject(self, reason: &str) {
+        match self {
+            HandlerMsg::Execute(req) => {
+                let _ = req.tx.send(Err(anyhow!("{reason}")));
+            }
+            HandlerMsg::GetState(req) => {
+                let _ = req.tx.send(Err(anyhow!("{reason}")));
+            }
+            HandlerMsg::Upgrade => {}
         }
     }
+}
+
+fn is_wrong_expected_version(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let sqlx_err = match cause.downcast_ref::<message_db::Error>() {
+            Some(message_db::Error::Database(err)) => err,
+            _ => match cause.downcast_ref::<sqlx::Error>() {
+                Some(err) => err,
+                None => return false,
+            },
+        };
+        // The only exception raised by message-db's `write_message` function
+        sqlx_err
+            .as_database_error()
+            .and_then(|err| err.code())
+            .map_or(false, |code| code == RAISE_EXCEPTION_CODE)
+    })
+}
 
-    Ok(result)
+fn event_from_message(mut message: GenericMessage) -> Result<Event> {
+    let ctx_json = message
+        .metadata
+        .properties
+        .remove("ctx")
+        .ok_or_else(|| anyhow!("missing ctx in event metadata"))?;
+    let ctx = serde_json::from_value(ctx_json).context("failed to deserialize ctx in metadata")?;
+    Ok(Event {
+        ctx,
+        event_type: message.msg_type,
+        payload: serde_json::to_vec(&message.data)?,
+    })
 }
 
 async fn save_events(
@@ -175,6 +716,17 @@ async fn save_events(
 ) -> Result<i64> {
     let stream_name_string = stream_name.to_string();
 
+    // Events continue the correlation and trace of the command causing them
+    let propagated: Vec<_> = [
+        CORRELATION_ID_PROPERTY,
+        TRACEPARENT_PROPERTY,
+        TRACESTATE_PROPERTY,
+    ]
+    .into_iter()
+    .filter_map(|name| Some((name, ctx.metadata.properties.get(name)?)))
+    .collect();
+    let correlation_stream_name = ctx.correlation_stream_name().unwrap_or(&ctx.stream_name);
+
     let event_ctxs: Vec<_> = events
         .iter()
         .map(|event| {
@@ -195,7 +747,12 @@ async fn save_events(
                     causation_message_stream_name: Some(&ctx.stream_name),
                     causation_message_position: Some(ctx.position),
                     causation_message_global_position: Some(ctx.global_position),
-                    properties: HashMap::from_iter([("ctx", event_ctx)]),
+                    correlation_stream_name: Some(correlation_stream_name),
+                    properties: propagated
+                        .iter()
+                        .copied()
+                        .chain([("ctx", event_ctx)])
+                        .collect(),
                     ..Default::default()
                 })
                 .build();
@@ -0,0 +1,115 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as AnyhowContext, Result};
+use sha2::{Digest, Sha256};
+use tokio::task;
+use tracing::{trace, warn};
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+/// Caches compiled components in a local directory.
+///
+/// Artifacts are keyed by the hash of the module binary, within a directory
+/// for each engine configuration, so artifacts are never loaded by an
+/// incompatible engine.
+#[derive(Clone)]
+pub struct CompileCache {
+    engine: Engine,
+    dir: Option<PathBuf>,
+}
+
+impl CompileCache {
+    /// Creates a compile cache storing artifacts in `dir`. Artifacts are not
+    /// cached if no directory is given.
+    pub fn new(engine: Engine, dir: Option<PathBuf>) -> Self {
+        let dir = dir.map(|dir| {
+            let mut hasher = DefaultHasher::new();
+            engine.precompile_compatibility_hash().hash(&mut hasher);
+            dir.join(format!("{:016x}", hasher.finish()))
+        });
+
+        CompileCache { engine, dir }
+    }
+
+    /// Loads a compiled component from the cache, compiling and caching it if
+    /// it's not cached yet.
+    pub async fn load(&self, binary: &[u8]) -> Result<Component> {
+        let cache = self.clone();
+        let binary = binary.to_vec();
+        task::spawn_blocking(move || cache.load_blocking(&binary)).await?
+    }
+
+    /// Compiles and caches a component ahead of time, unless already cached.
+    pub async fn precompile(&self, binary: &[u8]) -> Result<()> {
+        let cache = self.clone();
+        let binary = binary.to_vec();
+        task::spawn_blocking(move || cache.precompile_blocking(&binary)).await?
+    }
+
+    fn load_blocking(&self, binary: &[u8]) -> Result<Component> {
+        let Some(path) = self.artifact_path(binary) else {
+            return Component::from_binary(&self.engine, binary);
+        };
+
+        if path.exists() {
+            // SAFETY: artifacts are only written by this cache, using an engine with
+            // the same configuration
+            match unsafe { Component::deserialize_file(&self.engine, &path) } {
+                Ok(component) => {
+                    trace!(?path, "loaded compiled component from cache");
+                    return Ok(component);
+                }
+                Err(err) => {
+                    warn!(?path, "failed to load cached component, recompiling: {err}");
+                }
+            }
+        }
+
+        let component = Component::from_binary(&self.engine, binary)?;
+        if let Err(err) = write_artifact(&path, &component.serialize()?) {
+            warn!(?path, "failed to cache compiled component: {err}");
+        }
+
+        Ok(component)
+    }
+
+    fn precompile_blocking(&self, binary: &[u8]) -> Result<()> {
+        let Some(path) = self.artifact_path(binary) else {
+            return Ok(());
+        };
+        if path.exists() {
+            return Ok(());
+        }
+
+        let artifact = self
+            .engine
+            .precompile_component(binary)
+            .context("failed to compile module")?;
+        write_artifact(&path, &artifact)?;
+        trace!(?path, "precompiled component");
+
+        Ok(())
+    }
+
+    fn artifact_path(&self, binary: &[u8]) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{:x}.cwasm", Sha256::digest(binary))))
+    }
+}
+
+/// Writes an artifact to a temporary file first, so partially written
+/// artifacts are never loaded.
+fn write_artifact(path: &Path, artifact: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("failed to create compile cache directory")?;
+    }
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp_path, artifact).context("failed to write compiled component")?;
+    fs::rename(&tmp_path, path).context("failed to write compiled component")?;
+
+    Ok(())
+}
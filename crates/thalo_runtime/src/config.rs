@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::iter;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use semver::VersionReq;
+
+use crate::module::ModuleName;
+
+/// Interval at which the engine's epoch is incremented, used to interrupt
+/// guests exceeding their execution budget.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Runtime configuration.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of events after which a new aggregate state snapshot is taken.
+    ///
+    /// A value of `0` disables snapshots.
+    pub snapshot_interval: u64,
+    /// Maximum number of entity command handlers kept in memory.
+    ///
+    /// The least recently used idle handler is evicted when the capacity is
+    /// reached. Handlers with queued commands are kept, even above capacity.
+    pub handler_capacity: NonZeroUsize,
+    /// Number of command router tasks which entity streams are sharded
+    /// across.
+    pub router_shards: NonZeroUsize,
+    /// Retry policy for commands failing due to concurrent writes to an
+    /// entity stream.
+    pub conflict_retry: RetryPolicy,
+    /// Maximum time to wait for queued commands to be handled when shutting
+    /// down.
+    pub shutdown_timeout: Duration,
+    /// Consumer group membership of this node, splitting entity streams
+    /// across runtime nodes.
+    ///
+    /// A node without membership handles all entity streams.
+    pub consumer_group: Option<ConsumerGroup>,
+    /// Version requirements serving each module.
+    ///
+    /// Modules without a pinned version requirement are served by their
+    /// latest published version.
+    pub module_versions: HashMap<ModuleName, VersionReq>,
+    /// Number of instance slots reserved by the pooling allocator, shared by
+    /// all modules.
+    ///
+    /// Each module instance uses one slot per core instance it contains.
+    pub instance_slots: u32,
+    /// Directory caching compiled modules, or `None` to disable caching.
+    pub compile_cache_dir: Option<PathBuf>,
+    /// Resource limits applied to modules without their own limits.
+    pub default_module_limits: ModuleLimits,
+    /// Resource limits for specific modules.
+    pub module_limits: HashMap<ModuleName, ModuleLimits>,
+}
+
+/// Membership of a runtime node in a consumer group.
+///
+/// Entity streams are assigned to members by the hash of their cardinal ID,
+/// as done by message-db consumer groups, so each entity is handled by exactly
+/// one node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConsumerGroup {
+    /// Index of this node in the group, starting from `0`.
+    pub member: u32,
+    /// Number of nodes in the group.
+    pub size: u32,
+}
+
+/// Resource limits applied to guest module execution.
+#[derive(Clone, Debug)]
+pub struct ModuleLimits {
+    /// Maximum time a single call into the guest may run for.
+    ///
+    /// Guests are interrupted once the budget is exceeded, with a precision
+    /// of [`EPOCH_TICK`].
+    pub execution_budget: Duration,
+    /// Maximum number of 64 KiB pages each linear memory may grow to.
+    pub max_memory_pages: u32,
+    /// Maximum number of elements each table may grow to.
+    pub max_table_elements: u32,
+    /// Maximum number of core instances in the module's store.
+    pub max_instances: usize,
+    /// Maximum number of tables in the module's store.
+    pub max_tables: usize,
+    /// Maximum number of linear memories in the module's store.
+    pub max_memories: usize,
+    /// Number of instances of the module, allowing as many entities to
+    /// execute in parallel.
+    pub instance_pool_size: NonZeroUsize,
+}
+
+/// Bounded retry policy with exponential backoff.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of retries before giving up.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubling with each attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            snapshot_interval: 100,
+            handler_capacity: NonZeroUsize::new(10_000).unwrap(),
+            router_shards: thread::available_parallelism()
+                .unwrap_or_else(|_| NonZeroUsize::new(1).unwrap()),
+            conflict_retry: RetryPolicy::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            consumer_group: None,
+            module_versions: HashMap::new(),
+            instance_slots: 1_000,
+            compile_cache_dir: directories_next::ProjectDirs::from("", "thalo", "thalo")
+                .map(|dirs| dirs.cache_dir().join("components")),
+            default_module_limits: ModuleLimits::default(),
+            module_limits: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the resource limits for a module.
+    pub fn module_limits(&self, name: &ModuleName) -> &ModuleLimits {
+        self.module_limits
+            .get(name)
+            .unwrap_or(&self.default_module_limits)
+    }
+
+    /// Returns the limits of all modules.
+    pub fn all_module_limits(&self) -> impl Iterator<Item = &ModuleLimits> {
+        iter::once(&self.default_module_limits).chain(self.module_limits.values())
+    }
+}
+
+impl ConsumerGroup {
+    pub fn new(member: u32, size: u32) -> Result<Self> {
+        if member >= size {
+            bail!("consumer group member {member} must be less than the group size {size}");
+        }
+
+        Ok(ConsumerGroup { member, size })
+    }
+
+    /// Returns whether an entity stream is assigned to this member, given its
+    /// cardinal ID.
+    ///
+    /// Mirrors message-db's `@hash_64(cardinal_id) % size = member`, where
+    /// `hash_64` is the first 64 bits of the ID's md5 hash.
+    pub fn owns(&self, cardinal_id: &str) -> bool {
+        let digest = md5::compute(cardinal_id);
+        let hash = i64::from_be_bytes(digest[..8].try_into().unwrap());
+        hash.unsigned_abs() % u64::from(self.size) == u64::from(self.member)
+    }
+}
+
+impl ModuleLimits {
+    /// Returns the execution budget in epoch ticks, rounded up.
+    pub fn epoch_deadline(&self) -> u64 {
+        let ticks = self.execution_budget.as_nanos() / EPOCH_TICK.as_nanos();
+        let remainder = self.execution_budget.as_nanos() % EPOCH_TICK.as_nanos();
+        (ticks as u64 + u64::from(remainder > 0)).max(1)
+    }
+}
+
+impl Default for ModuleLimits {
+    fn default() -> Self {
+        ModuleLimits {
+            execution_budget: Duration::from_secs(5),
+            max_memory_pages: 1024,
+            max_table_elements: 10_000,
+            max_instances: 100,
+            max_tables: 100,
+            max_memories: 100,
+            instance_pool_size: NonZeroUsize::new(8).unwrap(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before retry number `attempt`, starting from `0`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_capped() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(1), Duration::from_millis(20));
+        assert_eq!(policy.backoff(3), Duration::from_millis(80));
+        assert_eq!(policy.backoff(4), Duration::from_millis(100));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn each_stream_is_owned_by_one_member() {
+        let size = 3;
+        let members: Vec<_> = (0..size)
+            .map(|member| ConsumerGroup::new(member, size).unwrap())
+            .collect();
+
+        let mut owned = vec![0; size as usize];
+        for id in 0..1_000 {
+            let owners: Vec<_> = members
+                .iter()
+                .filter(|group| group.owns(&id.to_string()))
+                .collect();
+            assert_eq!(owners.len(), 1, "stream {id} has {} owners", owners.len());
+            owned[owners[0].member as usize] += 1;
+        }
+        assert!(owned.iter().all(|count| *count > 0), "{owned:?}");
+    }
+
+    #[test]
+    fn consumer_group_member_must_be_in_group() {
+        assert!(ConsumerGroup::new(2, 2).is_err());
+    }
+
+    #[test]
+    fn epoch_deadline_rounds_up_to_a_tick() {
+        let limits = |execution_budget| ModuleLimits {
+            execution_budget,
+            ..ModuleLimits::default()
+        };
+
+        assert_eq!(limits(Duration::ZERO).epoch_deadline(), 1);
+        assert_eq!(limits(EPOCH_TICK).epoch_deadline(), 1);
+        assert_eq!(
+            limits(EPOCH_TICK + Duration::from_nanos(1)).epoch_deadline(),
+            2
+        );
+        assert_eq!(limits(EPOCH_TICK * 100).epoch_deadline(), 100);
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use message_db::database::{GetCategoryMessagesOpts, MessageStore, WriteMessageOpts};
+use message_db::message::MessageData;
+use message_db::stream_name::{Category, StreamName};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thalo::Context;
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::module::ModuleName;
+
+const DEAD_LETTER_MSG_TYPE: &str = "DeadLetter";
+const RETRIED_MSG_TYPE: &str = "Retried";
+const DISCARDED_MSG_TYPE: &str = "Discarded";
+/// Number of messages loaded per query when listing dead letters.
+const LIST_BATCH_SIZE: i64 = 1_000;
+
+/// Stores commands which failed to be handled in
+/// `<entity>:command+dlq-<id>` streams.
+///
+/// Dead letters are resolved by appending a `Retried` or `Discarded` message
+/// referencing them to the same stream.
+#[derive(Clone)]
+pub struct DeadLetterQueue {
+    message_store: MessageStore,
+}
+
+/// A command which failed to be handled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// Context of the original command message.
+    pub ctx: Context,
+    pub command: String,
+    pub payload: Value,
+    /// Chain of errors the command failed with, outermost first.
+    pub errors: Vec<String>,
+    /// Module version which failed to handle the command, if it was loaded.
+    pub module_version: Option<Version>,
+}
+
+/// An unresolved dead letter.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetterEntry {
+    /// Message ID of the dead letter.
+    pub id: Uuid,
+    pub stream_name: String,
+    pub dead_letter: DeadLetter,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Resolution {
+    dead_letter_id: Uuid,
+    /// ID of the resubmitted command, if retried.
+    #[serde(default)]
+    command_id: Option<Uuid>,
+}
+
+impl DeadLetterQueue {
+    pub fn new(message_store: MessageStore) -> Self {
+        DeadLetterQueue { message_store }
+    }
+
+    /// Writes a failed command to the dead letter stream of its entity.
+    pub async fn push(&self, dead_letter: &DeadLetter) -> Result<()> {
+        let stream_name = dead_letter_stream_name(&dead_letter.ctx.stream_name)?;
+        MessageStore::write_message(
+            &self.message_store,
+            &stream_name.to_string(),
+            DEAD_LETTER_MSG_TYPE,
+            dead_letter,
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to write dead letter")?;
+
+        trace!(
+            stream_name = %stream_name,
+            command_id = %dead_letter.ctx.id,
+            "dead lettered command"
+        );
+
+        Ok(())
+    }
+
+    /// Lists the unresolved dead letters of a module, oldest first.
+    pub async fn list(&self, name: &ModuleName) -> Result<Vec<DeadLetterEntry>> {
+        let category = format!("{}:command+dlq", Category::normalize(name));
+
+        let mut entries: Vec<DeadLetterEntry> = Vec::new();
+        let mut position = 1;
+        loop {
+            let opts = GetCategoryMessagesOpts::builder()
+                .position(position)
+                .batch_size(LIST_BATCH_SIZE)
+                .build();
+            let messages = MessageStore::get_category_messages::<MessageData, _>(
+                &self.message_store,
+                &category,
+                &opts,
+            )
+            .await?;
+            let Some(last_position) = messages.last().map(|message| message.global_position) else {
+                break;
+            };
+            let batch_len = messages.len();
+
+            for message in messages {
+                match message.msg_type.as_str() {
+                    DEAD_LETTER_MSG_TYPE => entries.push(DeadLetterEntry {
+                        id: message.id,
+                        stream_name: message.stream_name.to_string(),
+                        dead_letter: serde_json::from_value(message.data)?,
+                    }),
+                    RETRIED_MSG_TYPE | DISCARDED_MSG_TYPE => {
+                        let resolution: Resolution = serde_json::from_value(message.data)?;
+                        entries.retain(|entry| entry.id != resolution.dead_letter_id);
+                    }
+                    _ => {}
+                }
+            }
+
+            if (batch_len as i64) < LIST_BATCH_SIZE {
+                break;
+            }
+            position = last_position + 1;
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns an unresolved dead letter by its ID.
+    pub async fn get(&self, name: &ModuleName, id: Uuid) -> Result<DeadLetterEntry> {
+        self.list(name)
+            .await?
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| anyhow!("dead letter {id} not found"))
+    }
+
+    /// Marks a dead letter as retried with a resubmitted command.
+    pub async fn retried(&self, entry: &DeadLetterEntry, command_id: Uuid) -> Result<()> {
+        self.resolve(entry, RETRIED_MSG_TYPE, Some(command_id)).await
+    }
+
+    /// Marks a dead letter as discarded.
+    pub async fn discard(&self, entry: &DeadLetterEntry) -> Result<()> {
+        self.resolve(entry, DISCARDED_MSG_TYPE, None).await
+    }
+
+    async fn resolve(
+        &self,
+        entry: &DeadLetterEntry,
+        msg_type: &str,
+        command_id: Option<Uuid>,
+    ) -> Result<()> {
+        let resolution = Resolution {
+            dead_letter_id: entry.id,
+            command_id,
+        };
+        MessageStore::write_message(
+            &self.message_store,
+            &entry.stream_name,
+            msg_type,
+            &resolution,
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to resolve dead letter")?;
+
+        Ok(())
+    }
+}
+
+impl DeadLetter {
+    pub fn new(
+        ctx: Context,
+        command: String,
+        payload: Value,
+        err: &anyhow::Error,
+        module_version: Option<Version>,
+    ) -> Self {
+        DeadLetter {
+            ctx,
+            command,
+            payload,
+            errors: err.chain().map(|err| err.to_string()).collect(),
+            module_version,
+        }
+    }
+}
+
+fn dead_letter_stream_name(command_stream_name: &StreamName) -> Result<StreamName> {
+    let mut types = command_stream_name.category.types.clone();
+    types.push("dlq".to_string());
+    Ok(StreamName {
+        category: Category::new(command_stream_name.category.entity_name.clone(), types)?,
+        id: command_stream_name.id.clone(),
+    })
+}
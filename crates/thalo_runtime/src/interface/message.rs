@@ -2,31 +2,144 @@ use anyhow::Result;
 use bytes::Bytes;
 use message_db::message::GenericMessage;
 use quinn::RecvStream;
+use semver::VersionReq;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+pub use thalo::subscription::{
+    Subscribe, SubscriptionAck, SubscriptionRequest, SubscriptionResponse,
+};
+use thalo::TraceContext;
+use uuid::Uuid;
 
+use crate::command::EntityState;
+use crate::dead_letter::DeadLetterEntry;
 use crate::module::ModuleName;
+use crate::outcome::{Outcome, OutcomeEvent};
+use crate::runtime::EventPage;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Request {
     Execute {
         name: ModuleName,
         id: String,
+        /// Client supplied command ID, used to deduplicate submissions.
+        #[serde(default)]
+        command_id: Option<Uuid>,
         command: String,
         data: Vec<u8>,
+        /// Module version range to handle the command with.
+        #[serde(default)]
+        module_version: Option<VersionReq>,
+        /// Correlation ID to continue, defaulting to the command ID.
+        #[serde(default)]
+        correlation_id: Option<String>,
+        /// W3C trace context of the client.
+        #[serde(default)]
+        trace_context: Option<TraceContext>,
     },
     Publish {},
+    Pin {
+        name: ModuleName,
+        version: VersionReq,
+    },
+    ListDeadLetters {
+        name: ModuleName,
+    },
+    InspectDeadLetter {
+        name: ModuleName,
+        id: Uuid,
+    },
+    RetryDeadLetter {
+        name: ModuleName,
+        id: Uuid,
+    },
+    DiscardDeadLetter {
+        name: ModuleName,
+        id: Uuid,
+    },
+    QueryProjection {
+        name: ModuleName,
+        key: String,
+    },
+    CancelSchedule {
+        name: ModuleName,
+        entity_id: String,
+        id: String,
+    },
+    GetState {
+        name: ModuleName,
+        id: String,
+        /// Stream position to rebuild the state at, defaulting to the latest.
+        #[serde(default)]
+        at_position: Option<i64>,
+    },
+    ReadStream {
+        name: ModuleName,
+        id: String,
+        /// Stream position to read from.
+        #[serde(default)]
+        position: i64,
+        #[serde(default)]
+        batch_size: Option<i64>,
+        /// Event types to read, or all if empty.
+        #[serde(default)]
+        event_types: Vec<String>,
+    },
+    ReadCategory {
+        name: ModuleName,
+        /// Global position to read from.
+        #[serde(default)]
+        position: i64,
+        #[serde(default)]
+        batch_size: Option<i64>,
+        /// Event types to read, or all if empty.
+        #[serde(default)]
+        event_types: Vec<String>,
+    },
+    /// Subscribes to a category, pushing [`Response::EventBatch`]es.
+    ///
+    /// Encoded as [`SubscriptionRequest::Subscribe`] sent by consumers.
+    Subscribe(Subscribe),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Response {
     Executed(ExecutedResult),
     Published,
+    Pinned,
+    DeadLetters(Vec<DeadLetterEntry>),
+    DeadLetter(DeadLetterEntry),
+    DeadLetterRetried { command_id: Uuid },
+    DeadLetterDiscarded,
+    ProjectionEntry(Option<Value>),
+    ScheduleCancelled,
+    State(EntityState),
+    Events(EventPage),
+    /// Encoded as [`SubscriptionResponse::EventBatch`] received by consumers.
+    EventBatch(Vec<GenericMessage>),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ExecutedResult {
-    Events(Vec<GenericMessage>),
-    TimedOut,
+    /// The command was accepted, resulting in events.
+    Accepted(Vec<OutcomeEvent>),
+    /// The command was ignored, with an optional reason.
+    Ignored(Option<String>),
+    /// The command failed, with the chain of errors outermost first.
+    Failed(Vec<String>),
+    /// The command was submitted to the runtime node its entity is assigned
+    /// to, which records its outcome.
+    Submitted,
+}
+
+impl From<Outcome> for ExecutedResult {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Accepted { events } => ExecutedResult::Accepted(events),
+            Outcome::Ignored { reason } => ExecutedResult::Ignored(reason),
+            Outcome::Failed { errors } => ExecutedResult::Failed(errors),
+        }
+    }
 }
 
 /// Packs a response to be sent over the network.
@@ -61,3 +174,31 @@ pub async fn receive_raw(recv: &mut RecvStream) -> Result<Vec<u8>> {
     recv.read_exact(&mut buffer).await?;
     Ok(buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscription_request_is_encoded_as_request() {
+        let subscribe = Subscribe {
+            category: "counter".to_string(),
+            position: Some(5),
+            consumer_id: Some("consumer".to_string()),
+        };
+        let data =
+            rmp_serde::to_vec_named(&SubscriptionRequest::Subscribe(subscribe.clone())).unwrap();
+
+        let request: Request = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(request, Request::Subscribe(subscribe));
+    }
+
+    #[test]
+    fn event_batch_is_encoded_as_subscription_response() {
+        let data =
+            rmp_serde::to_vec_named(&Ok::<_, String>(Response::EventBatch(Vec::new()))).unwrap();
+
+        let response: Result<SubscriptionResponse, String> = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(response, Ok(SubscriptionResponse::EventBatch(Vec::new())));
+    }
+}
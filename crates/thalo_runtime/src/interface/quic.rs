@@ -1,3 +1,5 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -6,20 +8,32 @@ use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
 use esdl::schema::Schema;
-use futures::{StreamExt, TryFutureExt};
-use message_db::database::{MessageStore, SubscribeToCategoryOpts};
-use message_db::message::MessageData;
-use message_db::stream_name::{Category, StreamName};
-use quinn::{RecvStream, SendStream};
+use futures::TryFutureExt;
+use quinn::{RecvStream, SendStream, VarInt};
 use rustls::PrivateKey;
+use semver::VersionReq;
+use thalo::TraceContext;
 use tokio::fs;
-use tracing::{error, info, info_span, warn, Instrument};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{error, info, info_span, Instrument};
+use uuid::Uuid;
 
-use crate::interface::message::{pack, receive, receive_raw, ExecutedResult, Request, Response};
+use crate::interface::message::{
+    pack, receive, receive_raw, ExecutedResult, Request, Response, Subscribe, SubscriptionAck,
+};
 use crate::module::{ModuleName, SchemaModule};
-use crate::runtime::Runtime;
+use crate::runtime::{Runtime, ShuttingDownError, SUBSCRIPTION_POSITION_UPDATE_INTERVAL};
+use crate::telemetry;
 
 pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+/// Application close code sent to clients when the server shuts down.
+pub const SHUTDOWN_CLOSE_CODE: VarInt = VarInt::from_u32(0);
+/// Maximum number of batches sent to a subscriber ahead of its
+/// acknowledgements.
+pub const MAX_UNACKED_BATCHES: usize = 4;
+/// Interval at which a caught up subscription checks for new messages.
+const SUBSCRIPTION_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 pub async fn run(
     certs: Vec<rustls::Certificate>,
@@ -28,6 +42,7 @@ pub async fn run(
     stateless_retry: bool,
     listen: SocketAddr,
     runtime: Runtime,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<()> {
     let mut server_crypto = rustls::ServerConfig::builder()
         .with_safe_defaults()
@@ -49,7 +64,16 @@ pub async fn run(
     let endpoint = quinn::Endpoint::server(server_config, listen)?;
     info!("listening on {}", endpoint.local_addr()?);
 
-    while let Some(conn) = endpoint.accept().await {
+    tokio::pin!(shutdown);
+    loop {
+        let conn = tokio::select! {
+            conn = endpoint.accept() => match conn {
+                Some(conn) => conn,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+
         info!("connection incoming");
         let fut = handle_connection(runtime.clone(), conn);
         tokio::spawn(async move {
@@ -59,7 +83,15 @@ pub async fn run(
         });
     }
 
-    Ok(())
+    // Open connections are kept until queued commands are handled, so clients
+    // still receive their outcomes
+    info!("no longer accepting connections");
+    let res = runtime.shutdown().await;
+    endpoint.close(SHUTDOWN_CLOSE_CODE, b"server shutting down");
+    endpoint.wait_idle().await;
+    info!("closed endpoint");
+
+    res
 }
 
 pub async fn load_certs(
@@ -159,8 +191,20 @@ async fn handle_connection(runtime: Runtime, conn: quinn::Connecting) -> Result<
         info!("established");
 
         // Each stream initiated by the client constitutes a new request.
+        let mut shutdown = runtime.shutdown_signal();
+        let mut accepting = true;
         loop {
-            let stream = connection.accept_bi().await;
+            if accepting && *shutdown.borrow() {
+                // Streams opened before the client learns of the new limit are still
+                // accepted, and rejected by `handle_request`
+                connection.set_max_concurrent_bi_streams(0_u32.into());
+                accepting = false;
+                info!("no longer accepting requests");
+            }
+            let stream = tokio::select! {
+                stream = connection.accept_bi() => stream,
+                _ = shutdown.changed(), if accepting => continue,
+            };
             let stream = match stream {
                 Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
                     info!("connection closed");
@@ -192,14 +236,95 @@ async fn handle_request(
     (mut send, mut recv): (SendStream, RecvStream),
 ) -> Result<()> {
     let req: Request = receive(&mut recv).await?;
+    if runtime.is_shutting_down() {
+        return close_request(send, ShuttingDownError.into()).await;
+    }
     let resp = match req {
+        Request::Subscribe(Subscribe {
+            category,
+            position,
+            consumer_id,
+        }) => return handle_subscribe(&runtime, category, position, consumer_id, send, recv).await,
         Request::Execute {
             name,
             id,
+            command_id,
             command,
             data,
-        } => handle_execute(&runtime, name, id, command, data).await,
+            module_version,
+            correlation_id,
+            trace_context,
+        } => {
+            handle_execute(
+                &runtime,
+                name,
+                id,
+                command_id,
+                command,
+                data,
+                module_version,
+                correlation_id,
+                trace_context,
+            )
+            .await
+        }
         Request::Publish {} => handle_publish(&runtime, &mut recv).await,
+        Request::Pin { name, version } => handle_pin(&runtime, name, version).await,
+        Request::ListDeadLetters { name } => runtime
+            .list_dead_letters(&name)
+            .await
+            .map(Response::DeadLetters),
+        Request::InspectDeadLetter { name, id } => runtime
+            .get_dead_letter(&name, id)
+            .await
+            .map(Response::DeadLetter),
+        Request::RetryDeadLetter { name, id } => runtime
+            .retry_dead_letter(&name, id)
+            .await
+            .map(|command_id| Response::DeadLetterRetried { command_id }),
+        Request::DiscardDeadLetter { name, id } => runtime
+            .discard_dead_letter(&name, id)
+            .await
+            .map(|()| Response::DeadLetterDiscarded),
+        Request::QueryProjection { name, key } => runtime
+            .query_projection(&name, &key)
+            .await
+            .map(Response::ProjectionEntry),
+        Request::CancelSchedule {
+            name,
+            entity_id,
+            id,
+        } => runtime
+            .cancel_schedule(&name, &entity_id, &id)
+            .await
+            .map(|()| Response::ScheduleCancelled),
+        Request::GetState {
+            name,
+            id,
+            at_position,
+        } => runtime
+            .get_state(name, id, at_position)
+            .await
+            .map(Response::State),
+        Request::ReadStream {
+            name,
+            id,
+            position,
+            batch_size,
+            event_types,
+        } => runtime
+            .read_stream(&name, &id, position, batch_size, &event_types)
+            .await
+            .map(Response::Events),
+        Request::ReadCategory {
+            name,
+            position,
+            batch_size,
+            event_types,
+        } => runtime
+            .read_category(&name, position, batch_size, &event_types)
+            .await
+            .map(Response::Events),
     };
 
     let resp = resp.map_err(|err| {
@@ -220,49 +345,42 @@ async fn handle_request(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_execute(
     runtime: &Runtime,
     name: ModuleName,
     id: String,
+    command_id: Option<Uuid>,
     command: String,
     data: Vec<u8>,
+    module_version: Option<VersionReq>,
+    correlation_id: Option<String>,
+    trace_context: Option<TraceContext>,
 ) -> Result<Response> {
     let data = serde_json::from_slice(&data).context("invalid command data json")?;
-    let command_position = runtime.submit_command(&name, &id, &command, &data).await?;
-
-    let event_category = Category::normalize(&name);
-
-    let mut command_category: Category = event_category.parse()?;
-    command_category.types.push("command".to_string());
-    let command_stream_name = StreamName {
-        category: command_category,
-        id: Some(id.parse()?),
-    };
-
-    let condition = format!(
-        "metadata->>'causation_message_stream_name' = '{command_stream_name}' AND metadata->>'causation_message_position' = '{command_position}'"
-    );
-
-    let mut stream = MessageStore::subscribe_to_category::<MessageData, _>(
-        runtime.message_store(),
-        &event_category,
-        &SubscribeToCategoryOpts::builder()
-            .position_update_interval(0)
-            .condition(&condition)
-            .build(),
-    )
-    .await?;
+    let command_id = command_id.unwrap_or_else(Uuid::new_v4);
 
-    tokio::select! {
-        Some(result) = stream.next() => {
-            let events = result?;
-            Ok(Response::Executed(ExecutedResult::Events(events)))
-        }
-        _ = tokio::time::sleep(Duration::from_secs(10)) => {
-            warn!("command timed out when waiting for causation events");
-            Ok(Response::Executed(ExecutedResult::TimedOut))
-        }
+    // Continues the client's trace, if any
+    let span = info_span!("execute", %name, %id, %command_id, %command);
+    if let Some(trace_context) = &trace_context {
+        telemetry::set_parent(&span, trace_context);
     }
+    let outcome = runtime
+        .submit_and_execute(
+            &name,
+            &id,
+            command_id,
+            &command,
+            &data,
+            module_version.as_ref(),
+            correlation_id.as_deref(),
+        )
+        .instrument(span)
+        .await?;
+
+    Ok(Response::Executed(
+        outcome.map_or(ExecutedResult::Submitted, ExecutedResult::from),
+    ))
 }
 
 pub async fn handle_publish(runtime: &Runtime, recv: &mut RecvStream) -> Result<Response> {
@@ -275,3 +393,165 @@ pub async fn handle_publish(runtime: &Runtime, recv: &mut RecvStream) -> Result<
 
     Ok(Response::Published {})
 }
+
+/// Pushes batches of a category's messages to a subscriber until either side
+/// closes the stream.
+///
+/// At most [`MAX_UNACKED_BATCHES`] batches are sent ahead of the subscriber's
+/// acknowledgements. Acknowledged positions are recorded for subscribers with a
+/// consumer ID, so they resume from there when resubscribing.
+pub async fn handle_subscribe(
+    runtime: &Runtime,
+    category: String,
+    position: Option<i64>,
+    consumer_id: Option<String>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let mut position = match runtime
+        .subscription_start_position(&category, position, consumer_id.as_deref())
+        .await
+    {
+        Ok(position) => position,
+        Err(err) => return close_request(send, err).await,
+    };
+    info!(%category, position, "subscribed");
+
+    // Acknowledgements are read in their own task, as reading a message isn't
+    // cancel safe
+    let (ack_tx, mut ack_rx) = watch::channel(position - 1);
+    tokio::spawn(async move {
+        while let Ok(ack) = receive::<SubscriptionAck>(&mut recv).await {
+            if ack_tx.send(ack.position).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut shutdown = runtime.shutdown_signal();
+    // Last position and number of messages of each unacknowledged batch
+    let mut unacked = VecDeque::new();
+    let mut recorded_position = position - 1;
+    let mut acked_since_recorded = 0;
+    let result = loop {
+        if *shutdown.borrow() {
+            break Err(ShuttingDownError.into());
+        }
+        let acked_position = *ack_rx.borrow_and_update();
+        while let Some(&(last, len)) = unacked.front() {
+            if last > acked_position {
+                break;
+            }
+            unacked.pop_front();
+            acked_since_recorded += len as u64;
+        }
+        // Positions are recorded every so often like the runtime's own
+        // subscriptions, rather than on every acknowledgement
+        if acked_since_recorded >= SUBSCRIPTION_POSITION_UPDATE_INTERVAL
+            && acked_position > recorded_position
+        {
+            if let Some(consumer_id) = &consumer_id {
+                record_subscription_position(runtime, &category, consumer_id, acked_position).await;
+            }
+            recorded_position = acked_position;
+            acked_since_recorded = 0;
+        }
+
+        if unacked.len() >= MAX_UNACKED_BATCHES {
+            tokio::select! {
+                res = ack_rx.changed() => {
+                    if res.is_err() {
+                        break Ok(());
+                    }
+                }
+                _ = shutdown.changed() => {}
+            }
+            continue;
+        }
+
+        let messages = match runtime.read_subscription_batch(&category, position).await {
+            Ok(messages) => messages,
+            Err(err) => break Err(err),
+        };
+        let last_position = match messages.last() {
+            Some(message) => message.global_position,
+            None => {
+                tokio::select! {
+                    res = ack_rx.changed() => {
+                        if res.is_err() {
+                            break Ok(());
+                        }
+                    }
+                    _ = time::sleep(SUBSCRIPTION_POLL_INTERVAL) => {}
+                    _ = shutdown.changed() => {}
+                }
+                continue;
+            }
+        };
+
+        let len = messages.len();
+        let mut batch = pack(&Ok::<_, String>(Response::EventBatch(messages)))?;
+        if send.write_all_chunks(&mut batch).await.is_err() {
+            break Ok(());
+        }
+        position = last_position + 1;
+        unacked.push_back((last_position, len));
+    };
+
+    // Records the position acknowledged since it was last recorded, so a
+    // resubscribing consumer only receives the unacknowledged messages again
+    let acked_position = *ack_rx.borrow();
+    if let Some(consumer_id) = &consumer_id {
+        if acked_position > recorded_position {
+            record_subscription_position(runtime, &category, consumer_id, acked_position).await;
+        }
+    }
+
+    info!(%category, "unsubscribed");
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => close_request(send, err).await,
+    }
+}
+
+/// Records the position acknowledged by a subscription's consumer, logging
+/// failures since the position is recorded again later.
+async fn record_subscription_position(
+    runtime: &Runtime,
+    category: &str,
+    consumer_id: &str,
+    position: i64,
+) {
+    if let Err(err) = runtime
+        .record_subscription_position(category, consumer_id, position)
+        .await
+    {
+        error!(%category, "failed to record subscription position: {err}");
+    }
+}
+
+/// Replies to a request with an error, and terminates the stream.
+async fn close_request(mut send: SendStream, err: anyhow::Error) -> Result<()> {
+    let err = err
+        .chain()
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join(" - ");
+    let mut reply = pack(&Err::<Response, _>(err))?;
+    send.write_all_chunks(&mut reply).await?;
+    send.finish()
+        .await
+        .map_err(|e| anyhow!("failed to shutdown stream: {}", e))?;
+
+    Ok(())
+}
+
+pub async fn handle_pin(
+    runtime: &Runtime,
+    name: ModuleName,
+    version: VersionReq,
+) -> Result<Response> {
+    runtime.pin_module_version(name, version).await?;
+
+    Ok(Response::Pinned)
+}
@@ -1,5 +1,14 @@
 pub mod command;
+pub mod compile_cache;
+pub mod config;
+pub mod dead_letter;
 pub mod interface;
 pub mod module;
+pub mod outcome;
+pub mod process_manager;
+pub mod projection;
 pub mod registry;
 pub mod runtime;
+pub mod schedule;
+pub mod snapshot;
+pub mod telemetry;
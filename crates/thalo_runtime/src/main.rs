@@ -1,22 +1,25 @@
 mod cli;
 
+use clap::Parser;
 use tracing::error;
-use tracing_subscriber::fmt::format::FmtSpan;
-use tracing_subscriber::EnvFilter;
+
+use crate::cli::Cli;
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive("thalo_runtime=info".parse().unwrap())
-                .from_env_lossy(),
-        )
-        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        .init();
+    if let Err(err) = cli.init_tracing() {
+        eprintln!("failed to initialize tracing: {err}");
+        std::process::exit(1);
+    }
+
+    let res = cli::start(cli).await;
+    // Flushes spans which are yet to be exported
+    opentelemetry::global::shutdown_tracer_provider();
 
-    if let Err(err) = cli::start().await {
+    if let Err(err) = res {
         error!("{err}");
         err.chain()
             .skip(1)
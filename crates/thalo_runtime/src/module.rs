@@ -1,38 +1,96 @@
+mod instance;
+pub mod process_manager;
+pub mod projection;
 pub mod wit_aggregate;
+pub mod wit_log;
+pub mod wit_process_manager;
+pub mod wit_projection;
+pub mod wit_scheduler;
+pub mod wit_types;
 
-use std::ops::DerefMut;
 use std::path::Path;
-use std::sync::Arc;
-use std::{borrow, fmt, str};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{borrow, fmt, slice, str};
 
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
 use chrono::{TimeZone, Utc};
 use derive_more::{Deref, DerefMut};
 use esdl::schema::Schema;
 use host::WasiCtx;
+use message_db::stream_name::StreamName;
 use semver::Version;
 use serde::{Deserialize, Serialize};
-use thalo::Context;
-use tokio::sync::Mutex;
-use tracing::trace;
+use thalo::{Context, ScheduleChange, ScheduledCommand};
+use thiserror::Error;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{event, trace, Level};
+use uuid::Uuid;
 use wasi_cap_std_sync::WasiCtxBuilder;
 use wasmtime::component::{Component, Linker};
-use wasmtime::{Engine, Store};
+use wasmtime::{Engine, ResourceLimiter, Store, Trap};
 
+use self::instance::{Instance, Loader};
 use self::wit_aggregate::{Aggregate, Command};
+use crate::config::ModuleLimits;
 
-#[derive(Clone)]
+/// Size of a WebAssembly linear memory page.
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+/// Target of the log events of guests.
+const GUEST_LOG_TARGET: &str = "thalo::guest";
+
+/// A compiled module, with a pool of instances to execute it on.
+///
+/// Each instance has its own store, letting entities of the same module
+/// execute in parallel.
 pub struct Module {
-    aggregate: Aggregate,
-    id: ModuleID,
-    store: Arc<Mutex<Store<WasiCtx>>>,
+    loader: Loader<Aggregate>,
+    idle: Mutex<Vec<Instance<Aggregate>>>,
+    permits: Semaphore,
 }
 
 pub struct ModuleInstance {
-    aggregate: Aggregate,
-    id: ModuleID,
+    module: Arc<Module>,
     state: Vec<u8>,
-    store: Arc<Mutex<Store<WasiCtx>>>,
+}
+
+/// An instance taken from a module's pool.
+struct PooledInstance<'a> {
+    module: &'a Module,
+    instance: Instance<Aggregate>,
+    _permit: SemaphorePermit<'a>,
+}
+
+/// Data owned by a module's store.
+struct StoreData {
+    module_id: ModuleID,
+    wasi: WasiCtx,
+    limiter: Limiter,
+    log_context: LogContext,
+    /// Changes to scheduled commands requested by the guest during a call.
+    schedule_changes: Vec<ScheduleChange>,
+}
+
+/// Identifies the entity and command being handled in guest log events.
+#[derive(Default)]
+struct LogContext {
+    entity_id: Option<String>,
+    command_id: Option<Uuid>,
+}
+
+/// Enforces a module's resource limits on its store.
+///
+/// Memories and tables are counted as they're created while instantiating, so
+/// exceeding their counts returns a [`ResourceLimitExceeded`] error, like
+/// growing them beyond their limits.
+struct Limiter {
+    module_name: ModuleName,
+    limits: ModuleLimits,
+    /// Whether the store's instance is being instantiated, when memories and
+    /// tables are created.
+    instantiating: bool,
+    memories: usize,
+    tables: usize,
 }
 
 /// A module verified against a schema.
@@ -52,12 +110,47 @@ pub struct ModuleID {
 )]
 pub struct ModuleName(String);
 
+/// The world implemented by a module's component.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleKind {
+    Aggregate,
+    ProcessManager,
+    Projection,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ExecuteResult {
     Events(Vec<Event>),
     Ignored(Option<String>),
 }
 
+/// A guest call was interrupted for running longer than the module's
+/// execution budget.
+#[derive(Debug, Error)]
+#[error("execution budget exceeded: module '{module_name}' ran for longer than {budget:?}")]
+pub struct ExecutionBudgetExceeded {
+    pub module_name: ModuleName,
+    pub budget: Duration,
+}
+
+/// A guest exceeded one of the module's resource limits.
+#[derive(Debug, Error)]
+#[error("resource limit exceeded: module '{module_name}' exceeded its limit of {limit} {resource}")]
+pub struct ResourceLimitExceeded {
+    pub module_name: ModuleName,
+    pub resource: Resource,
+    pub limit: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    MemoryPages,
+    TableElements,
+    Instances,
+    Tables,
+    Memories,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Event {
     pub ctx: Context,
@@ -73,70 +166,134 @@ pub struct EventRef<'a> {
 }
 
 impl Module {
-    pub async fn from_file<T>(engine: Engine, id: ModuleID, file: T) -> Result<Self>
+    pub async fn from_file<T>(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        file: T,
+    ) -> Result<Self>
     where
         T: AsRef<Path> + fmt::Debug,
     {
-        let mut store = Store::new(&engine, WasiCtxBuilder::new().build());
         let component = Component::from_file(&engine, &file).unwrap();
-        let mut linker = Linker::new(&engine);
-        host::add_to_linker(&mut linker, |x| x)?;
-
-        let (aggregate, _instance) =
-            wit_aggregate::instantiate_async(&mut store, &component, &linker)
-                .await
-                .context("failed to instantiate module")?;
+        let module = Module::from_component(engine, id, limits, &component).await?;
 
         trace!(?file, "loaded module from file");
 
-        Ok(Module {
-            aggregate,
-            id,
-            store: Arc::new(Mutex::new(store)),
-        })
+        Ok(module)
     }
 
-    pub async fn from_binary(engine: Engine, id: ModuleID, binary: &[u8]) -> Result<Self> {
-        let mut store = Store::new(&engine, WasiCtxBuilder::new().build());
+    pub async fn from_binary(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        binary: &[u8],
+    ) -> Result<Self> {
         let component = Component::from_binary(&engine, binary).unwrap();
-        let mut linker = Linker::new(&engine);
-        host::add_to_linker(&mut linker, |x| x)?;
-
-        let (aggregate, _instance) =
-            wit_aggregate::instantiate_async(&mut store, &component, &linker)
-                .await
-                .context("failed to instantiate module")?;
+        let module = Module::from_component(engine, id, limits, &component).await?;
 
         trace!("loaded module from binary");
 
+        Ok(module)
+    }
+
+    /// Creates a module from an already compiled component.
+    pub async fn from_component(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        component: &Component,
+    ) -> Result<Self> {
+        let permits = Semaphore::new(limits.instance_pool_size.get());
+        let loader = Loader::new(engine, id, limits, component)?;
+        // Instantiate eagerly to surface errors when loading the module
+        let instance = loader.instantiate().await?;
+
         Ok(Module {
-            aggregate,
-            id,
-            store: Arc::new(Mutex::new(store)),
+            loader,
+            idle: Mutex::new(vec![instance]),
+            permits,
         })
     }
 
-    pub async fn init(&self, id: String) -> Result<ModuleInstance> {
-        let aggregate = self.aggregate;
-        let state = {
-            let mut store = self.store.lock().await;
-            aggregate.init(store.deref_mut(), &id).await??
-        };
+    pub async fn init(self: &Arc<Self>, id: String) -> Result<ModuleInstance> {
+        let mut pooled = self.acquire().await?;
+        pooled.set_log_context(Some(id.clone()), None);
+        let start = self.loader.start_call(&mut pooled.instance);
+        let result = pooled
+            .instance
+            .guest
+            .init(&mut pooled.instance.store, &id)
+            .await;
+        let state = self.loader.finish_call("init", start, result)?;
+        pooled.release();
+        let state = state?;
 
         trace!(%id, "initialized module");
 
         Ok(ModuleInstance {
-            aggregate,
-            id: self.id.clone(),
+            module: Arc::clone(self),
+            state,
+        })
+    }
+
+    /// Creates an instance from previously serialized state, such as a
+    /// snapshot, without calling `init`.
+    pub fn restore(self: &Arc<Self>, state: Vec<u8>) -> ModuleInstance {
+        ModuleInstance {
+            module: Arc::clone(self),
             state,
-            store: Arc::clone(&self.store),
+        }
+    }
+
+    /// Takes an idle instance from the pool, instantiating a new one if none
+    /// are idle.
+    ///
+    /// Waits for an instance to be released if the pool is exhausted.
+    async fn acquire(&self) -> Result<PooledInstance<'_>> {
+        let permit = self.permits.acquire().await?;
+        let idle = self.idle.lock().unwrap().pop();
+        let instance = match idle {
+            Some(instance) => instance,
+            None => self.loader.instantiate().await?,
+        };
+
+        Ok(PooledInstance {
+            module: self,
+            instance,
+            _permit: permit,
         })
     }
 }
 
+impl PooledInstance<'_> {
+    /// Sets the entity and command tagging the guest's log events.
+    fn set_log_context(&mut self, entity_id: Option<String>, command_id: Option<Uuid>) {
+        self.instance.store.data_mut().log_context = LogContext {
+            entity_id,
+            command_id,
+        };
+    }
+
+    /// Returns the instance to the pool.
+    ///
+    /// Instances which trapped must not be released, and are dropped instead.
+    fn release(self) {
+        self.module.idle.lock().unwrap().push(self.instance);
+    }
+}
+
 impl ModuleInstance {
     pub fn id(&self) -> &ModuleID {
-        &self.id
+        &self.module.loader.id
+    }
+
+    pub fn module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    pub fn state(&self) -> &[u8] {
+        &self.state
     }
 
     pub async fn apply(&mut self, events: &[EventRef<'_>]) -> Result<()> {
@@ -144,6 +301,7 @@ impl ModuleInstance {
             return Ok(());
         }
 
+        let entity_id = stream_entity_id(&events[0].ctx.stream_name);
         let event_ctxs: Vec<_> = events
             .iter()
             .map(|event| (wit_aggregate::ContextResult::from(event.ctx), event))
@@ -157,30 +315,53 @@ impl ModuleInstance {
             })
             .collect();
 
-        self.state = {
-            let mut store = self.store.lock().await;
-            self.aggregate
-                .apply(store.deref_mut(), &self.state, &events)
-                .await??
-        };
+        let module = &self.module;
+        let mut pooled = module.acquire().await?;
+        pooled.set_log_context(entity_id, None);
+        // Events are applied one per call, each with its own execution budget, so
+        // replaying a long stream can't exceed the budget of a single call
+        for event in &events {
+            let start = module.loader.start_call(&mut pooled.instance);
+            let result = pooled
+                .instance
+                .guest
+                .apply(
+                    &mut pooled.instance.store,
+                    &self.state,
+                    slice::from_ref(event),
+                )
+                .await;
+            let result = module.loader.finish_call("apply", start, result)?;
+            self.state = match result {
+                Ok(state) => state,
+                Err(err) => {
+                    pooled.release();
+                    return Err(err.into());
+                }
+            };
+        }
+        pooled.release();
 
         trace!("applied {} event(s)", events.len());
 
         Ok(())
     }
 
+    /// Handles a command, returning its result along with the changes to
+    /// scheduled commands requested by the aggregate.
     pub async fn handle(
         &self,
         ctx: &Context,
         command_name: &str,
         payload: &[u8],
-    ) -> Result<ExecuteResult> {
+    ) -> Result<(ExecuteResult, Vec<ScheduleChange>)> {
         let command = Command {
             command: command_name,
             payload,
         };
 
-        let mut store = self.store.lock().await;
+        let entity_id = stream_entity_id(&ctx.stream_name);
+        let command_id = ctx.id;
         let metadata = serde_json::to_vec(&ctx.metadata).unwrap();
         let ctx = self::wit_aggregate::ContextParam {
             id: &ctx.id.to_string(),
@@ -190,37 +371,168 @@ impl ModuleInstance {
             metadata: &metadata,
             time: ctx.time.timestamp_millis(),
         };
-        let result = self
-            .aggregate
-            .handle(store.deref_mut(), &self.state, ctx, command)
-            .await?;
+        let mut pooled = self.module.acquire().await?;
+        pooled.set_log_context(entity_id, Some(command_id));
+        let loader = &self.module.loader;
+        let start = loader.start_call(&mut pooled.instance);
+        let result = pooled
+            .instance
+            .guest
+            .handle(&mut pooled.instance.store, &self.state, ctx, command)
+            .await;
+        let result = loader.finish_call("handle", start, result)?;
+        let schedules = std::mem::take(&mut pooled.instance.store.data_mut().schedule_changes);
+        pooled.release();
         match result {
-            Ok(events) => events
-                .into_iter()
-                .map(Event::try_from)
-                .collect::<Result<Vec<_>, _>>()
-                .map(ExecuteResult::Events),
+            Ok(events) => {
+                let events = events
+                    .into_iter()
+                    .map(Event::try_from)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((ExecuteResult::Events(events), schedules))
+            }
             Err(wit_aggregate::Error::Ignore(reason)) => {
                 match &reason {
                     Some(reason) => trace!("command ignored with reason: '{reason}'"),
                     None => trace!("command ignored"),
                 }
-                Ok(ExecuteResult::Ignored(reason))
+                Ok((ExecuteResult::Ignored(reason), Vec::new()))
             }
             Err(err) => Err(anyhow!(err)),
         }
     }
+}
 
-    pub async fn handle_and_apply(
-        &mut self,
-        ctx: &Context,
-        command: &str,
-        payload: &[u8],
-    ) -> Result<ExecuteResult> {
-        let result = self.handle(ctx, command, payload).await?;
-        let event_refs: Vec<_> = result.events().iter().map(Event::as_ref).collect();
-        self.apply(&event_refs).await?;
-        Ok(result)
+/// Returns whether the guest trapped, leaving its instance unusable.
+pub fn is_trap(err: &anyhow::Error) -> bool {
+    err.is::<ExecutionBudgetExceeded>() || err.is::<ResourceLimitExceeded>() || err.is::<Trap>()
+}
+
+/// Creates a linker providing the host imports of all module worlds.
+fn new_linker(engine: &Engine) -> Result<Linker<StoreData>> {
+    let mut linker = Linker::new(engine);
+    host::add_to_linker(&mut linker, |data: &mut StoreData| &mut data.wasi)?;
+    wit_log::add_to_linker(&mut linker, |data: &mut StoreData| data)?;
+    wit_scheduler::add_to_linker(&mut linker, |data: &mut StoreData| data)?;
+    Ok(linker)
+}
+
+fn new_store(engine: &Engine, id: &ModuleID, limits: &ModuleLimits) -> Store<StoreData> {
+    let mut store = Store::new(
+        engine,
+        StoreData {
+            module_id: id.clone(),
+            wasi: WasiCtxBuilder::new().build(),
+            limiter: Limiter {
+                module_name: id.name.clone(),
+                limits: limits.clone(),
+                instantiating: true,
+                memories: 0,
+                tables: 0,
+            },
+            log_context: LogContext::default(),
+            schedule_changes: Vec::new(),
+        },
+    );
+    store.limiter(|data| &mut data.limiter);
+    reset_execution_budget(&mut store, limits);
+    store
+}
+
+/// Gives the guest a full execution budget, called before every call into the
+/// guest so the budget applies to each call on its own.
+fn reset_execution_budget<T>(store: &mut Store<T>, limits: &ModuleLimits) {
+    store.set_epoch_deadline(limits.epoch_deadline());
+}
+
+/// Returns the entity ID of a command or event stream.
+fn stream_entity_id(stream_name: &StreamName) -> Option<String> {
+    stream_name
+        .id
+        .as_ref()
+        .map(|id| id.cardinal_id().to_string())
+}
+
+/// Maps epoch interruptions to an [`ExecutionBudgetExceeded`] error.
+fn interrupted(err: anyhow::Error, id: &ModuleID, limits: &ModuleLimits) -> anyhow::Error {
+    match err.downcast_ref::<Trap>() {
+        Some(Trap::Interrupt) => ExecutionBudgetExceeded {
+            module_name: id.name.clone(),
+            budget: limits.execution_budget,
+        }
+        .into(),
+        _ => err,
+    }
+}
+
+/// Checks that instantiating a component stays within the module's limit of
+/// core instances, before instantiating it.
+///
+/// Unlike memories and tables, instances aren't reported to the store's
+/// limiter, so they're counted from the component's core instance sections.
+pub fn check_instance_count(binary: &[u8], id: &ModuleID, limits: &ModuleLimits) -> Result<()> {
+    let mut instances = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(binary) {
+        if let wasmparser::Payload::InstanceSection(reader) = payload? {
+            for instance in reader {
+                if let wasmparser::Instance::Instantiate { .. } = instance? {
+                    instances += 1;
+                }
+            }
+        }
+    }
+    if instances > limits.max_instances {
+        return Err(ResourceLimitExceeded {
+            module_name: id.name.clone(),
+            resource: Resource::Instances,
+            limit: limits.max_instances,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+impl ModuleKind {
+    /// Detects the world implemented by a component from the interface it
+    /// exports.
+    pub async fn detect(
+        engine: &Engine,
+        id: &ModuleID,
+        limits: &ModuleLimits,
+        component: &Component,
+    ) -> Result<Self> {
+        let linker = new_linker(engine)?;
+        let mut store = new_store(engine, id, limits);
+        let instance = linker
+            .instantiate_async(&mut store, component)
+            .await
+            .map_err(|err| interrupted(err, id, limits))
+            .context("failed to instantiate module")?;
+
+        let mut exports = instance.exports(&mut store);
+        let mut exports = exports.root();
+        if exports.instance("aggregate").is_some() {
+            Ok(ModuleKind::Aggregate)
+        } else if exports.instance("process-manager").is_some() {
+            Ok(ModuleKind::ProcessManager)
+        } else if exports.instance("projection").is_some() {
+            Ok(ModuleKind::Projection)
+        } else {
+            bail!(
+                "module exports none of the \"aggregate\", \"process-manager\" or \"projection\" instances"
+            )
+        }
+    }
+}
+
+impl fmt::Display for ModuleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleKind::Aggregate => write!(f, "aggregate"),
+            ModuleKind::ProcessManager => write!(f, "process manager"),
+            ModuleKind::Projection => write!(f, "projection"),
+        }
     }
 }
 
@@ -293,6 +605,149 @@ impl borrow::Borrow<str> for ModuleName {
     }
 }
 
+impl wit_log::Log for StoreData {
+    /// Forwards a guest log message to the runtime's `tracing` subscriber.
+    fn log(&mut self, level: wit_log::Level, message: String) -> Result<()> {
+        macro_rules! guest_event {
+            ($level:expr) => {
+                event!(
+                    target: GUEST_LOG_TARGET,
+                    $level,
+                    module_name = %self.module_id.name,
+                    module_version = %self.module_id.version,
+                    entity_id = self.log_context.entity_id.as_deref(),
+                    command_id = self.log_context.command_id.map(tracing::field::display),
+                    "{message}"
+                )
+            };
+        }
+
+        match level {
+            wit_log::Level::Trace => guest_event!(Level::TRACE),
+            wit_log::Level::Debug => guest_event!(Level::DEBUG),
+            wit_log::Level::Info => guest_event!(Level::INFO),
+            wit_log::Level::Warn => guest_event!(Level::WARN),
+            wit_log::Level::Error => guest_event!(Level::ERROR),
+        }
+
+        Ok(())
+    }
+}
+
+impl wit_scheduler::Scheduler for StoreData {
+    /// Collects a command scheduled by the guest, saved once the command being
+    /// handled is.
+    fn schedule(
+        &mut self,
+        id: String,
+        aggregate: String,
+        entity_id: String,
+        command: String,
+        payload: Vec<u8>,
+        due: i64,
+    ) -> Result<()> {
+        let due = Utc
+            .timestamp_millis_opt(due)
+            .single()
+            .ok_or_else(|| anyhow!("invalid due time for schedule '{id}'"))?;
+        self.schedule_changes
+            .push(ScheduleChange::Schedule(ScheduledCommand {
+                id,
+                aggregate,
+                entity_id,
+                command,
+                payload,
+                due,
+            }));
+
+        Ok(())
+    }
+
+    fn cancel(&mut self, id: String) -> Result<()> {
+        self.schedule_changes.push(ScheduleChange::Cancel(id));
+
+        Ok(())
+    }
+}
+
+impl Limiter {
+    fn exceeded(&self, resource: Resource, limit: usize) -> anyhow::Error {
+        ResourceLimitExceeded {
+            module_name: self.module_name.clone(),
+            resource,
+            limit,
+        }
+        .into()
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        if self.instantiating && current == 0 {
+            // Called for each memory as it's created
+            self.memories += 1;
+            if self.memories > self.limits.max_memories {
+                return Err(self.exceeded(Resource::Memories, self.limits.max_memories));
+            }
+        }
+        let max_pages = self.limits.max_memory_pages as usize;
+        if desired > max_pages.saturating_mul(WASM_PAGE_SIZE) {
+            return Err(self.exceeded(Resource::MemoryPages, max_pages));
+        }
+
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, current: u32, desired: u32, _maximum: Option<u32>) -> Result<bool> {
+        if self.instantiating && current == 0 {
+            // Called for each table as it's created
+            self.tables += 1;
+            if self.tables > self.limits.max_tables {
+                return Err(self.exceeded(Resource::Tables, self.limits.max_tables));
+            }
+        }
+        if desired > self.limits.max_table_elements {
+            return Err(self.exceeded(
+                Resource::TableElements,
+                self.limits.max_table_elements as usize,
+            ));
+        }
+
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.max_instances
+    }
+
+    // Counted by the limiter itself, as wasmtime's errors don't say which count
+    // was exceeded
+    fn tables(&self) -> usize {
+        usize::MAX
+    }
+
+    fn memories(&self) -> usize {
+        usize::MAX
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::MemoryPages => write!(f, "memory pages"),
+            Resource::TableElements => write!(f, "table elements"),
+            Resource::Instances => write!(f, "instances"),
+            Resource::Tables => write!(f, "tables"),
+            Resource::Memories => write!(f, "memories"),
+        }
+    }
+}
+
 impl ExecuteResult {
     pub fn events(&self) -> &[Event] {
         match self {
@@ -340,14 +795,14 @@ impl wit_aggregate::ContextResult {
             bail!("invalid timestamp");
         };
 
-        Ok(Context {
-            id: self.id.parse()?,
-            stream_name: self.stream_name.parse()?,
-            position: self.position,
-            global_position: self.global_position,
-            metadata: serde_json::from_slice(&self.metadata)?,
+        Ok(Context::new(
+            self.id.parse()?,
+            self.stream_name.parse()?,
+            self.position,
+            self.global_position,
+            serde_json::from_slice(&self.metadata)?,
             time,
-        })
+        ))
     }
 }
 
@@ -363,3 +818,61 @@ impl From<&Context> for wit_aggregate::ContextResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Caller, Config, Func, Instance as CoreInstance, Module as CoreModule};
+
+    use super::*;
+    use crate::config::EPOCH_TICK;
+
+    /// A guest whose `apply` uses up one epoch tick per call, before
+    /// reaching an epoch check on entering `check`.
+    const TICKING_GUEST: &str = r#"
+        (module
+            (import "host" "tick" (func $tick))
+            (func $check)
+            (func (export "apply")
+                call $tick
+                call $check))
+    "#;
+
+    /// Calls `apply` once per event, optionally resetting the execution
+    /// budget before each call.
+    fn replay(events: usize, reset_per_call: bool) -> Result<()> {
+        let engine = Engine::new(Config::new().epoch_interruption(true))?;
+        let module = CoreModule::new(&engine, TICKING_GUEST)?;
+        let mut store = Store::new(&engine, ());
+        let tick = Func::wrap(&mut store, |caller: Caller<'_, ()>| {
+            caller.engine().increment_epoch()
+        });
+        let instance = CoreInstance::new(&mut store, &module, &[tick.into()])?;
+        let apply = instance.get_typed_func::<(), ()>(&mut store, "apply")?;
+
+        // Each call fits in the budget, but two calls don't
+        let limits = ModuleLimits {
+            execution_budget: EPOCH_TICK * 2,
+            ..ModuleLimits::default()
+        };
+        reset_execution_budget(&mut store, &limits);
+        for _ in 0..events {
+            if reset_per_call {
+                reset_execution_budget(&mut store, &limits);
+            }
+            apply.call(&mut store, ())?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn replay_exceeding_one_budget_is_interrupted() {
+        let err = replay(10, false).unwrap_err();
+        assert!(matches!(err.downcast_ref::<Trap>(), Some(Trap::Interrupt)));
+    }
+
+    #[test]
+    fn replay_gets_a_budget_per_call() {
+        replay(10, true).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use std::marker::PhantomData;
+use std::time::Instant;
+
+use anyhow::{Context as AnyhowContext, Result};
+use metrics::histogram;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::trace;
+use wasmtime::component::{self, Component, InstancePre};
+use wasmtime::{Engine, Store};
+
+use super::wit_aggregate::{self, Aggregate};
+use super::wit_process_manager::{self, ProcessManager};
+use super::wit_projection::{self, Projection};
+use super::{
+    interrupted, is_trap, new_linker, new_store, reset_execution_budget, ModuleID, StoreData,
+};
+use crate::config::ModuleLimits;
+use crate::telemetry::{self, MODULE_INSTANTIATE_DURATION};
+
+/// The exports of a module world, looked up on an instance of its component.
+pub(super) trait Guest: Sized {
+    fn new(store: &mut Store<StoreData>, instance: &component::Instance) -> Result<Self>;
+}
+
+/// Instantiates a compiled component with a module's limits, and wraps the
+/// calls into its instances.
+pub(super) struct Loader<G> {
+    pub(super) id: ModuleID,
+    pub(super) limits: ModuleLimits,
+    engine: Engine,
+    instance_pre: InstancePre<StoreData>,
+    guest: PhantomData<fn() -> G>,
+}
+
+/// An instance of a module, with its own store.
+pub(super) struct Instance<G> {
+    pub(super) guest: G,
+    pub(super) store: Store<StoreData>,
+}
+
+/// A module keeping a single instance, for worlds handling events one at a
+/// time.
+///
+/// The instance is replaced if it traps.
+pub(super) struct SingleInstance<G> {
+    loader: Loader<G>,
+    instance: Mutex<Option<Instance<G>>>,
+}
+
+/// A call into the instance of a [`SingleInstance`], holding its lock.
+pub(super) struct Call<'a, G> {
+    loader: &'a Loader<G>,
+    guard: MutexGuard<'a, Option<Instance<G>>>,
+    function: &'static str,
+    start: Instant,
+}
+
+impl<G: Guest> Loader<G> {
+    pub(super) fn new(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        component: &Component,
+    ) -> Result<Self> {
+        let linker = new_linker(&engine)?;
+        let instance_pre = linker.instantiate_pre(component)?;
+
+        Ok(Loader {
+            id,
+            limits,
+            engine,
+            instance_pre,
+            guest: PhantomData,
+        })
+    }
+
+    pub(super) async fn instantiate(&self) -> Result<Instance<G>> {
+        let start = Instant::now();
+        let mut store = new_store(&self.engine, &self.id, &self.limits);
+        let instance = self
+            .instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(|err| interrupted(err, &self.id, &self.limits))
+            .context("failed to instantiate module")?;
+        store.data_mut().limiter.instantiating = false;
+        let guest = G::new(&mut store, &instance)?;
+        histogram!(
+            MODULE_INSTANTIATE_DURATION,
+            start.elapsed().as_secs_f64(),
+            "module" => self.id.name.to_string()
+        );
+
+        trace!(module_name = %self.id.name, "instantiated module");
+
+        Ok(Instance { guest, store })
+    }
+}
+
+impl<G> Loader<G> {
+    /// Gives an instance a full execution budget for a call, returning the
+    /// time the call starts.
+    pub(super) fn start_call(&self, instance: &mut Instance<G>) -> Instant {
+        reset_execution_budget(&mut instance.store, &self.limits);
+        Instant::now()
+    }
+
+    /// Records the duration of a call, mapping an interruption of the guest to
+    /// an [`ExecutionBudgetExceeded`](super::ExecutionBudgetExceeded) error.
+    pub(super) fn finish_call<T>(
+        &self,
+        function: &str,
+        start: Instant,
+        result: Result<T>,
+    ) -> Result<T> {
+        let result = result.map_err(|err| interrupted(err, &self.id, &self.limits));
+        telemetry::record_wasm_call(&self.id, function, start);
+        result
+    }
+}
+
+impl<G: Guest> SingleInstance<G> {
+    /// Creates a module from an already compiled component.
+    pub(super) async fn from_component(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        component: &Component,
+    ) -> Result<Self> {
+        let loader = Loader::new(engine, id, limits, component)?;
+        // Instantiate eagerly to surface errors when loading the module
+        let instance = loader.instantiate().await?;
+
+        Ok(SingleInstance {
+            loader,
+            instance: Mutex::new(Some(instance)),
+        })
+    }
+
+    pub(super) fn id(&self) -> &ModuleID {
+        &self.loader.id
+    }
+
+    /// Locks the instance for a call to `function`, instantiating a new one
+    /// if the previous one trapped.
+    pub(super) async fn call(&self, function: &'static str) -> Result<Call<'_, G>> {
+        let mut guard = self.instance.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.loader.instantiate().await?);
+        }
+        let start = self
+            .loader
+            .start_call(guard.as_mut().expect("instance acquired"));
+
+        Ok(Call {
+            loader: &self.loader,
+            guard,
+            function,
+            start,
+        })
+    }
+}
+
+impl<G> Call<'_, G> {
+    pub(super) fn instance(&mut self) -> &mut Instance<G> {
+        self.guard.as_mut().expect("instance acquired")
+    }
+
+    /// Finishes the call with its result, dropping the instance if the guest
+    /// trapped, leaving it unusable.
+    pub(super) fn finish<T>(mut self, result: Result<T>) -> Result<T> {
+        let result = self.loader.finish_call(self.function, self.start, result);
+        if let Err(err) = &result {
+            if is_trap(err) {
+                *self.guard = None;
+            }
+        }
+        result
+    }
+}
+
+impl Guest for Aggregate {
+    fn new(store: &mut Store<StoreData>, instance: &component::Instance) -> Result<Self> {
+        wit_aggregate::new(store, instance)
+    }
+}
+
+impl Guest for ProcessManager {
+    fn new(store: &mut Store<StoreData>, instance: &component::Instance) -> Result<Self> {
+        wit_process_manager::new(store, instance)
+    }
+}
+
+impl Guest for Projection {
+    fn new(store: &mut Store<StoreData>, instance: &component::Instance) -> Result<Self> {
+        wit_projection::new(store, instance)
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Result};
+use thalo::ProcessCommand;
+use tracing::trace;
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+use super::instance::SingleInstance;
+use super::wit_aggregate::ContextResult;
+use super::wit_process_manager::{EventParam, ProcessManager};
+use super::{EventRef, ModuleID};
+use crate::config::ModuleLimits;
+
+/// A compiled process manager module.
+///
+/// Events are handled one at a time, so a single instance is kept, and
+/// replaced if it traps.
+pub struct ProcessManagerModule {
+    instance: SingleInstance<ProcessManager>,
+}
+
+/// The result of a process handling an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessOutput {
+    pub state: Vec<u8>,
+    pub commands: Vec<ProcessCommand>,
+}
+
+impl ProcessManagerModule {
+    /// Creates a process manager from an already compiled component.
+    pub async fn from_component(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        component: &Component,
+    ) -> Result<Self> {
+        let instance = SingleInstance::from_component(engine, id, limits, component).await?;
+
+        Ok(ProcessManagerModule { instance })
+    }
+
+    pub fn id(&self) -> &ModuleID {
+        self.instance.id()
+    }
+
+    /// Returns the categories of the events subscribed to.
+    pub async fn categories(&self) -> Result<Vec<String>> {
+        let mut call = self.instance.call("categories").await?;
+        let instance = call.instance();
+        let result = instance.guest.categories(&mut instance.store).await;
+        call.finish(result)
+    }
+
+    /// Returns the initial state of a process.
+    pub async fn init(&self, id: &str) -> Result<Vec<u8>> {
+        let mut call = self.instance.call("init").await?;
+        let instance = call.instance();
+        let result = instance.guest.init(&mut instance.store, id).await;
+        let state = call.finish(result)??;
+
+        trace!(%id, "initialized process");
+
+        Ok(state)
+    }
+
+    /// Returns the ID of the process an event is routed to, if any.
+    pub async fn route(&self, event: EventRef<'_>) -> Result<Option<String>> {
+        let ctx = ContextResult::from(event.ctx);
+        let event = EventParam {
+            ctx: ctx.as_param(),
+            event_type: event.event_type,
+            payload: event.payload,
+        };
+
+        let mut call = self.instance.call("route").await?;
+        let instance = call.instance();
+        let result = instance.guest.route(&mut instance.store, event).await;
+        Ok(call.finish(result)??)
+    }
+
+    /// Handles an event with a process's state, returning its new state and
+    /// the commands to submit.
+    pub async fn handle(&self, state: &[u8], event: EventRef<'_>) -> Result<ProcessOutput> {
+        let ctx = ContextResult::from(event.ctx);
+        let event = EventParam {
+            ctx: ctx.as_param(),
+            event_type: event.event_type,
+            payload: event.payload,
+        };
+
+        let mut call = self.instance.call("handle").await?;
+        let instance = call.instance();
+        let result = instance
+            .guest
+            .handle(&mut instance.store, state, event)
+            .await;
+        let output = call.finish(result)?.map_err(|err| anyhow!(err))?;
+
+        Ok(ProcessOutput {
+            state: output.state,
+            commands: output
+                .commands
+                .into_iter()
+                .map(|command| ProcessCommand {
+                    aggregate: command.aggregate,
+                    id: command.id,
+                    command: command.command,
+                    payload: command.payload,
+                })
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+use super::instance::SingleInstance;
+use super::wit_aggregate::ContextResult;
+use super::wit_projection::{EntryParam, EventParam, OperationResult, Projection};
+use super::{EventRef, ModuleID};
+use crate::config::ModuleLimits;
+
+/// A compiled projection module.
+///
+/// Events are handled one at a time, so a single instance is kept, and
+/// replaced if it traps.
+pub struct ProjectionModule {
+    instance: SingleInstance<Projection>,
+}
+
+/// A change to an entry of a projection's table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Set { key: String, value: Vec<u8> },
+    Delete { key: String },
+}
+
+impl ProjectionModule {
+    /// Creates a projection from an already compiled component.
+    pub async fn from_component(
+        engine: Engine,
+        id: ModuleID,
+        limits: ModuleLimits,
+        component: &Component,
+    ) -> Result<Self> {
+        let instance = SingleInstance::from_component(engine, id, limits, component).await?;
+
+        Ok(ProjectionModule { instance })
+    }
+
+    pub fn id(&self) -> &ModuleID {
+        self.instance.id()
+    }
+
+    /// Returns the categories of the events subscribed to.
+    pub async fn categories(&self) -> Result<Vec<String>> {
+        let mut call = self.instance.call("categories").await?;
+        let instance = call.instance();
+        let result = instance.guest.categories(&mut instance.store).await;
+        call.finish(result)
+    }
+
+    /// Returns the keys of the entries an event reads or updates.
+    pub async fn keys(&self, event: EventRef<'_>) -> Result<Vec<String>> {
+        let ctx = ContextResult::from(event.ctx);
+        let event = EventParam {
+            ctx: ctx.as_param(),
+            event_type: event.event_type,
+            payload: event.payload,
+        };
+
+        let mut call = self.instance.call("keys").await?;
+        let instance = call.instance();
+        let result = instance.guest.keys(&mut instance.store, event).await;
+        Ok(call.finish(result)??)
+    }
+
+    /// Handles an event with the existing entries of its keys, returning the
+    /// changes to the projection's table.
+    pub async fn handle(
+        &self,
+        event: EventRef<'_>,
+        entries: &[(String, Vec<u8>)],
+    ) -> Result<Vec<Operation>> {
+        let ctx = ContextResult::from(event.ctx);
+        let event = EventParam {
+            ctx: ctx.as_param(),
+            event_type: event.event_type,
+            payload: event.payload,
+        };
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(key, value)| EntryParam { key, value })
+            .collect();
+
+        let mut call = self.instance.call("handle").await?;
+        let instance = call.instance();
+        let result = instance
+            .guest
+            .handle(&mut instance.store, event, &entries)
+            .await;
+        let operations = call.finish(result)?.map_err(|err| anyhow!(err))?;
+
+        Ok(operations
+            .into_iter()
+            .map(|operation| match operation {
+                OperationResult::Set(entry) => Operation::Set {
+                    key: entry.key,
+                    value: entry.value,
+                },
+                OperationResult::Delete(key) => Operation::Delete { key },
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,45 @@
+// wit_bindgen_host_wasmtime_rust::generate!({
+//     // name: "log",
+//     path: "log.wit",
+//     async: true
+// });
+
+#[derive(
+    wasmtime::component::ComponentType, wasmtime::component::Lift, wasmtime::component::Lower,
+)]
+#[component(enum)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    #[component(name = "trace")]
+    Trace,
+    #[component(name = "debug")]
+    Debug,
+    #[component(name = "info")]
+    Info,
+    #[component(name = "warn")]
+    Warn,
+    #[component(name = "error")]
+    Error,
+}
+
+pub trait Log: Sized {
+    fn log(&mut self, level: Level, message: String) -> anyhow::Result<()>;
+}
+
+pub fn add_to_linker<T, U>(
+    linker: &mut wasmtime::component::Linker<T>,
+    get: impl Fn(&mut T) -> &mut U + Send + Sync + Copy + 'static,
+) -> anyhow::Result<()>
+where
+    U: Log,
+{
+    let mut inst = linker.instance("log")?;
+    inst.func_wrap(
+        "log",
+        move |mut caller: wasmtime::StoreContextMut<'_, T>, (arg0, arg1): (Level, String)| {
+            let host = get(caller.data_mut());
+            host.log(arg0, arg1)
+        },
+    )?;
+    Ok(())
+}
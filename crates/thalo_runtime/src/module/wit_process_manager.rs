@@ -0,0 +1,155 @@
+use anyhow::anyhow;
+
+// Records shared with the aggregate world are structurally identical
+pub use super::wit_aggregate::{StateParam, StateResult};
+pub use super::wit_types::{ContextParam, Error, EventParam};
+
+#[derive(
+    wasmtime::component::ComponentType, wasmtime::component::Lift, wasmtime::component::Lower,
+)]
+#[component(record)]
+#[derive(Clone, Debug)]
+pub struct CommandResult {
+    #[component(name = "aggregate")]
+    pub aggregate: String,
+    #[component(name = "id")]
+    pub id: String,
+    #[component(name = "command")]
+    pub command: String,
+    #[component(name = "payload")]
+    pub payload: Vec<u8>,
+}
+
+#[derive(
+    wasmtime::component::ComponentType, wasmtime::component::Lift, wasmtime::component::Lower,
+)]
+#[component(record)]
+#[derive(Clone, Debug)]
+pub struct OutputResult {
+    #[component(name = "state")]
+    pub state: StateResult,
+    #[component(name = "commands")]
+    pub commands: Vec<CommandResult>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessManager {
+    categories: wasmtime::component::Func,
+    init: wasmtime::component::Func,
+    route: wasmtime::component::Func,
+    handle: wasmtime::component::Func,
+}
+impl ProcessManager {
+    pub fn new(
+        exports: &mut wasmtime::component::ExportInstance<'_, '_>,
+    ) -> anyhow::Result<ProcessManager> {
+        let categories = *exports
+            .typed_func::<(), (Vec<String>,)>("categories")?
+            .func();
+        let init = *exports
+            .typed_func::<(&str,), (Result<StateResult, Error>,)>("init")?
+            .func();
+        let route = *exports
+            .typed_func::<(EventParam<'_>,), (Result<Option<String>, Error>,)>("route")?
+            .func();
+        let handle = *exports
+            .typed_func::<(StateParam<'_>, EventParam<'_>), (Result<OutputResult, Error>,)>(
+                "handle",
+            )?
+            .func();
+        Ok(ProcessManager {
+            categories,
+            init,
+            route,
+            handle,
+        })
+    }
+    pub async fn categories<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+    ) -> anyhow::Result<Vec<String>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<(), (Vec<String>,)>::new_unchecked(self.categories)
+        };
+        let (ret0,) = callee.call_async(store.as_context_mut(), ()).await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+    pub async fn init<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+        arg0: &str,
+    ) -> anyhow::Result<Result<StateResult, Error>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<(&str,), (Result<StateResult, Error>,)>::new_unchecked(
+                self.init,
+            )
+        };
+        let (ret0,) = callee.call_async(store.as_context_mut(), (arg0,)).await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+    pub async fn route<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+        arg0: EventParam<'_>,
+    ) -> anyhow::Result<Result<Option<String>, Error>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<
+                (EventParam<'_>,),
+                (Result<Option<String>, Error>,),
+            >::new_unchecked(self.route)
+        };
+        let (ret0,) = callee.call_async(store.as_context_mut(), (arg0,)).await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+    pub async fn handle<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+        arg0: StateParam<'_>,
+        arg1: EventParam<'_>,
+    ) -> anyhow::Result<Result<OutputResult, Error>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<
+                (StateParam<'_>, EventParam<'_>),
+                (Result<OutputResult, Error>,),
+            >::new_unchecked(self.handle)
+        };
+        let (ret0,) = callee
+            .call_async(store.as_context_mut(), (arg0, arg1))
+            .await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+}
+
+/// Low-level creation wrapper for wrapping up the exports
+/// of the `instance` provided in this structure of wasm
+/// exports.
+pub fn new(
+    mut store: impl wasmtime::AsContextMut,
+    instance: &wasmtime::component::Instance,
+) -> anyhow::Result<ProcessManager> {
+    let mut store = store.as_context_mut();
+    let mut exports = instance.exports(&mut store);
+    let mut exports = exports.root();
+    let process_manager = ProcessManager::new(
+        &mut exports
+            .instance("process-manager")
+            .ok_or_else(|| anyhow!("no exported instance \"process-manager\""))?,
+    )?;
+    Ok(process_manager)
+}
@@ -0,0 +1,136 @@
+use anyhow::anyhow;
+
+pub use super::wit_types::{ContextParam, Error, EventParam};
+
+#[derive(wasmtime::component::ComponentType, wasmtime::component::Lower)]
+#[component(record)]
+#[derive(Clone, Debug)]
+pub struct EntryParam<'a> {
+    #[component(name = "key")]
+    pub key: &'a str,
+    #[component(name = "value")]
+    pub value: &'a [u8],
+}
+
+#[derive(
+    wasmtime::component::ComponentType, wasmtime::component::Lift, wasmtime::component::Lower,
+)]
+#[component(record)]
+#[derive(Clone, Debug)]
+pub struct EntryResult {
+    #[component(name = "key")]
+    pub key: String,
+    #[component(name = "value")]
+    pub value: Vec<u8>,
+}
+
+#[derive(
+    wasmtime::component::ComponentType, wasmtime::component::Lift, wasmtime::component::Lower,
+)]
+#[component(variant)]
+#[derive(Clone, Debug)]
+pub enum OperationResult {
+    #[component(name = "set")]
+    Set(EntryResult),
+    #[component(name = "delete")]
+    Delete(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Projection {
+    categories: wasmtime::component::Func,
+    keys: wasmtime::component::Func,
+    handle: wasmtime::component::Func,
+}
+impl Projection {
+    pub fn new(
+        exports: &mut wasmtime::component::ExportInstance<'_, '_>,
+    ) -> anyhow::Result<Projection> {
+        let categories = *exports
+            .typed_func::<(), (Vec<String>,)>("categories")?
+            .func();
+        let keys = *exports
+            .typed_func::<(EventParam<'_>,), (Result<Vec<String>, Error>,)>("keys")?
+            .func();
+        let handle = *exports
+            .typed_func::<(EventParam<'_>, &[EntryParam<'_>]), (Result<Vec<OperationResult>, Error>,)>(
+                "handle",
+            )?
+            .func();
+        Ok(Projection {
+            categories,
+            keys,
+            handle,
+        })
+    }
+    pub async fn categories<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+    ) -> anyhow::Result<Vec<String>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<(), (Vec<String>,)>::new_unchecked(self.categories)
+        };
+        let (ret0,) = callee.call_async(store.as_context_mut(), ()).await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+    pub async fn keys<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+        arg0: EventParam<'_>,
+    ) -> anyhow::Result<Result<Vec<String>, Error>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<(EventParam<'_>,), (Result<Vec<String>, Error>,)>::new_unchecked(
+                self.keys,
+            )
+        };
+        let (ret0,) = callee.call_async(store.as_context_mut(), (arg0,)).await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+    pub async fn handle<S: wasmtime::AsContextMut>(
+        &self,
+        mut store: S,
+        arg0: EventParam<'_>,
+        arg1: &[EntryParam<'_>],
+    ) -> anyhow::Result<Result<Vec<OperationResult>, Error>>
+    where
+        <S as wasmtime::AsContext>::Data: Send,
+    {
+        let callee = unsafe {
+            wasmtime::component::TypedFunc::<
+                (EventParam<'_>, &[EntryParam<'_>]),
+                (Result<Vec<OperationResult>, Error>,),
+            >::new_unchecked(self.handle)
+        };
+        let (ret0,) = callee
+            .call_async(store.as_context_mut(), (arg0, arg1))
+            .await?;
+        callee.post_return_async(store.as_context_mut()).await?;
+        Ok(ret0)
+    }
+}
+
+/// Low-level creation wrapper for wrapping up the exports
+/// of the `instance` provided in this structure of wasm
+/// exports.
+pub fn new(
+    mut store: impl wasmtime::AsContextMut,
+    instance: &wasmtime::component::Instance,
+) -> anyhow::Result<Projection> {
+    let mut store = store.as_context_mut();
+    let mut exports = instance.exports(&mut store);
+    let mut exports = exports.root();
+    let projection = Projection::new(
+        &mut exports
+            .instance("projection")
+            .ok_or_else(|| anyhow!("no exported instance \"projection\""))?,
+    )?;
+    Ok(projection)
+}
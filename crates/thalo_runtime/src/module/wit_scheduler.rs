@@ -0,0 +1,52 @@
+// wit_bindgen_host_wasmtime_rust::generate!({
+//     // name: "scheduler",
+//     path: "scheduler.wit",
+//     async: true
+// });
+
+pub trait Scheduler: Sized {
+    fn schedule(
+        &mut self,
+        id: String,
+        aggregate: String,
+        entity_id: String,
+        command: String,
+        payload: Vec<u8>,
+        due: i64,
+    ) -> anyhow::Result<()>;
+
+    fn cancel(&mut self, id: String) -> anyhow::Result<()>;
+}
+
+pub fn add_to_linker<T, U>(
+    linker: &mut wasmtime::component::Linker<T>,
+    get: impl Fn(&mut T) -> &mut U + Send + Sync + Copy + 'static,
+) -> anyhow::Result<()>
+where
+    U: Scheduler,
+{
+    let mut inst = linker.instance("scheduler")?;
+    inst.func_wrap(
+        "schedule",
+        move |mut caller: wasmtime::StoreContextMut<'_, T>,
+              (arg0, arg1, arg2, arg3, arg4, arg5): (
+            String,
+            String,
+            String,
+            String,
+            Vec<u8>,
+            i64,
+        )| {
+            let host = get(caller.data_mut());
+            host.schedule(arg0, arg1, arg2, arg3, arg4, arg5)
+        },
+    )?;
+    inst.func_wrap(
+        "cancel",
+        move |mut caller: wasmtime::StoreContextMut<'_, T>, (arg0,): (String,)| {
+            let host = get(caller.data_mut());
+            host.cancel(arg0)
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,54 @@
+// wit_bindgen_host_wasmtime_rust::generate!({
+//     // name: "types",
+//     path: "types.wit",
+//     async: true
+// });
+
+// Records shared with the aggregate world are structurally identical
+pub use super::wit_aggregate::{ContextParam, EventParam};
+
+#[derive(
+    wasmtime::component::ComponentType, wasmtime::component::Lift, wasmtime::component::Lower,
+)]
+#[component(variant)]
+#[derive(Clone, Debug)]
+pub enum Error {
+    #[component(name = "handle")]
+    Handle(String),
+    #[component(name = "deserialize-command")]
+    DeserializeCommand(String),
+    #[component(name = "deserialize-context")]
+    DeserializeContext(String),
+    #[component(name = "deserialize-event")]
+    DeserializeEvent(String),
+    #[component(name = "deserialize-state")]
+    DeserializeState(String),
+    #[component(name = "serialize-command")]
+    SerializeCommand(String),
+    #[component(name = "serialize-event")]
+    SerializeEvent(String),
+    #[component(name = "serialize-state")]
+    SerializeState(String),
+    #[component(name = "unknown-command")]
+    UnknownCommand,
+    #[component(name = "unknown-event")]
+    UnknownEvent,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Handle(msg) => write!(f, "failed to handle event: {msg}"),
+            Error::DeserializeCommand(msg) => write!(f, "failed to deserialize command: {msg}"),
+            Error::DeserializeContext(msg) => write!(f, "failed to deserialize context: {msg}"),
+            Error::DeserializeEvent(msg) => write!(f, "failed to deserialize event: {msg}"),
+            Error::DeserializeState(msg) => write!(f, "failed to deserialize state: {msg}"),
+            Error::SerializeCommand(msg) => write!(f, "failed to serialize command: {msg}"),
+            Error::SerializeEvent(msg) => write!(f, "failed to serialize event: {msg}"),
+            Error::SerializeState(msg) => write!(f, "failed to serialize state: {msg}"),
+            Error::UnknownCommand => write!(f, "unknown command"),
+            Error::UnknownEvent => write!(f, "unknown event"),
+        }
+    }
+}
+impl std::error::Error for Error {}
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use message_db::database::{GetStreamMessagesOpts, MessageStore, WriteMessageOpts};
+use message_db::stream_name::{Category, StreamName};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thalo::Context;
+use tokio::sync::oneshot;
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::module::{Event, ExecuteResult};
+
+/// Number of outcomes loaded per query when looking up an outcome.
+const FIND_BATCH_SIZE: i64 = 1_000;
+/// Number of latest outcomes read for the last handled command position, as
+/// outcomes of an entity's commands are recorded concurrently and may land
+/// slightly out of order.
+const RECENT_OUTCOMES: i64 = 100;
+
+/// Records the outcome of handled commands in
+/// `<entity>:command+outcome-<id>` streams, letting clients know whether a
+/// command was accepted, ignored or failed.
+#[derive(Clone)]
+pub struct OutcomeStore {
+    message_store: MessageStore,
+}
+
+/// Notifies callers waiting for the outcome of commands handled by this node.
+#[derive(Clone, Default)]
+pub struct OutcomeWaiters {
+    waiters: Arc<Mutex<HashMap<Uuid, Vec<oneshot::Sender<Outcome>>>>>,
+}
+
+/// Receives the outcome of a command, registered before the command is
+/// written so its outcome can't be missed.
+pub struct OutcomeWaiter {
+    command_id: Uuid,
+    rx: oneshot::Receiver<Outcome>,
+    waiters: OutcomeWaiters,
+}
+
+/// The outcome of a handled command.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome")]
+pub enum Outcome {
+    /// The command was accepted, resulting in events.
+    Accepted { events: Vec<OutcomeEvent> },
+    /// The command was ignored by the aggregate.
+    Ignored { reason: Option<String> },
+    /// The command failed, with the chain of errors outermost first.
+    Failed { errors: Vec<String> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutcomeEvent {
+    pub event_type: String,
+    pub data: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedOutcome {
+    pub command_id: Uuid,
+    /// Position of the command in its command stream.
+    #[serde(default)]
+    pub command_position: Option<i64>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl OutcomeStore {
+    pub fn new(message_store: MessageStore) -> Self {
+        OutcomeStore { message_store }
+    }
+
+    /// Records the outcome of a command, given the context of the command
+    /// message.
+    pub async fn record(&self, ctx: &Context, outcome: Outcome) -> Result<()> {
+        let stream_name = outcome_stream_name(&ctx.stream_name)?;
+        let msg_type = outcome.msg_type();
+        let recorded = RecordedOutcome {
+            command_id: ctx.id,
+            command_position: Some(ctx.position),
+            outcome,
+        };
+        MessageStore::write_message(
+            &self.message_store,
+            &stream_name.to_string(),
+            msg_type,
+            &recorded,
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to record command outcome")?;
+
+        trace!(
+            stream_name = %stream_name,
+            command_id = %ctx.id,
+            outcome = msg_type,
+            "recorded command outcome"
+        );
+
+        Ok(())
+    }
+
+    /// Finds the recorded outcome of a command, given the command's stream.
+    pub async fn find(
+        &self,
+        command_stream_name: &StreamName,
+        command_id: Uuid,
+    ) -> Result<Option<Outcome>> {
+        let stream_name = outcome_stream_name(command_stream_name)?.to_string();
+        let mut position = 0;
+        loop {
+            let opts = GetStreamMessagesOpts::builder()
+                .position(position)
+                .batch_size(FIND_BATCH_SIZE)
+                .build();
+            let messages = MessageStore::get_stream_messages::<RecordedOutcome, _>(
+                &self.message_store,
+                &stream_name,
+                &opts,
+            )
+            .await?;
+            let Some(last_position) = messages.last().map(|message| message.position) else {
+                return Ok(None);
+            };
+            let batch_len = messages.len();
+
+            if let Some(message) = messages
+                .into_iter()
+                .find(|message| message.data.command_id == command_id)
+            {
+                return Ok(Some(message.data.outcome));
+            }
+
+            if (batch_len as i64) < FIND_BATCH_SIZE {
+                return Ok(None);
+            }
+            position = last_position + 1;
+        }
+    }
+
+    /// Returns the highest command stream position of the latest recorded
+    /// outcomes of an entity.
+    pub async fn last_command_position(
+        &self,
+        command_stream_name: &StreamName,
+    ) -> Result<Option<i64>> {
+        let stream_name = outcome_stream_name(command_stream_name)?.to_string();
+        let last = MessageStore::get_last_stream_message::<RecordedOutcome, _>(
+            &self.message_store,
+            &stream_name,
+            None,
+        )
+        .await
+        .context("failed to load last command outcome")?;
+        let last_position = match last {
+            Some(last) => last.position,
+            None => return Ok(None),
+        };
+
+        let opts = GetStreamMessagesOpts::builder()
+            .position((last_position - RECENT_OUTCOMES + 1).max(0))
+            .batch_size(RECENT_OUTCOMES)
+            .build();
+        let messages = MessageStore::get_stream_messages::<RecordedOutcome, _>(
+            &self.message_store,
+            &stream_name,
+            &opts,
+        )
+        .await
+        .context("failed to load recent command outcomes")?;
+
+        Ok(messages
+            .into_iter()
+            .filter_map(|message| message.data.command_position)
+            .max())
+    }
+}
+
+impl OutcomeWaiters {
+    /// Registers interest in the outcome of a command.
+    pub fn wait(&self, command_id: Uuid) -> OutcomeWaiter {
+        let (tx, rx) = oneshot::channel();
+        self.waiters
+            .lock()
+            .unwrap()
+            .entry(command_id)
+            .or_default()
+            .push(tx);
+
+        OutcomeWaiter {
+            command_id,
+            rx,
+            waiters: self.clone(),
+        }
+    }
+
+    /// Sends the outcome of a command to everyone waiting for it.
+    pub fn notify(&self, command_id: Uuid, outcome: &Outcome) {
+        let senders = self.waiters.lock().unwrap().remove(&command_id);
+        for tx in senders.into_iter().flatten() {
+            let _ = tx.send(outcome.clone());
+        }
+    }
+}
+
+impl OutcomeWaiter {
+    pub async fn outcome(&mut self) -> Result<Outcome> {
+        (&mut self.rx)
+            .await
+            .map_err(|_| anyhow!("command {} was not handled", self.command_id))
+    }
+}
+
+impl Drop for OutcomeWaiter {
+    fn drop(&mut self) {
+        self.rx.close();
+        let mut waiters = self.waiters.waiters.lock().unwrap();
+        if let Some(senders) = waiters.get_mut(&self.command_id) {
+            senders.retain(|tx| !tx.is_closed());
+            if senders.is_empty() {
+                waiters.remove(&self.command_id);
+            }
+        }
+    }
+}
+
+impl Outcome {
+    pub fn new(result: &Result<ExecuteResult>) -> Self {
+        match result {
+            Ok(ExecuteResult::Events(events)) => Outcome::Accepted {
+                events: events.iter().map(OutcomeEvent::from).collect(),
+            },
+            Ok(ExecuteResult::Ignored(reason)) => Outcome::Ignored {
+                reason: reason.clone(),
+            },
+            Err(err) => Outcome::Failed {
+                errors: err.chain().map(|err| err.to_string()).collect(),
+            },
+        }
+    }
+
+    fn msg_type(&self) -> &'static str {
+        match self {
+            Outcome::Accepted { .. } => "CommandAccepted",
+            Outcome::Ignored { .. } => "CommandIgnored",
+            Outcome::Failed { .. } => "CommandFailed",
+        }
+    }
+}
+
+impl From<&Event> for OutcomeEvent {
+    fn from(event: &Event) -> Self {
+        OutcomeEvent {
+            event_type: event.event_type.clone(),
+            // Saved events always have a json payload
+            data: serde_json::from_slice(&event.payload).unwrap_or(Value::Null),
+        }
+    }
+}
+
+fn outcome_stream_name(command_stream_name: &StreamName) -> Result<StreamName> {
+    let mut types = command_stream_name.category.types.clone();
+    types.push("outcome".to_string());
+    Ok(StreamName {
+        category: Category::new(command_stream_name.category.entity_name.clone(), types)?,
+        id: command_stream_name.id.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored() -> Outcome {
+        Outcome::Ignored { reason: None }
+    }
+
+    #[tokio::test]
+    async fn notify_sends_outcome_to_every_waiter() {
+        let waiters = OutcomeWaiters::default();
+        let command_id = Uuid::new_v4();
+        let mut first = waiters.wait(command_id);
+        let mut second = waiters.wait(command_id);
+        let mut other = waiters.wait(Uuid::new_v4());
+
+        waiters.notify(command_id, &ignored());
+        assert_eq!(first.outcome().await.unwrap(), ignored());
+        assert_eq!(second.outcome().await.unwrap(), ignored());
+        assert!(other.rx.try_recv().is_err());
+        assert_eq!(waiters.waiters.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn notify_without_waiters_is_dropped() {
+        let waiters = OutcomeWaiters::default();
+        let command_id = Uuid::new_v4();
+        waiters.notify(command_id, &ignored());
+
+        // Only outcomes notified after registering are received
+        let mut waiter = waiters.wait(command_id);
+        assert!(waiter.rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn dropping_waiter_unregisters_it() {
+        let waiters = OutcomeWaiters::default();
+        let command_id = Uuid::new_v4();
+        let first = waiters.wait(command_id);
+        let second = waiters.wait(command_id);
+
+        drop(first);
+        assert_eq!(waiters.waiters.lock().unwrap()[&command_id].len(), 1);
+        drop(second);
+        assert!(waiters.waiters.lock().unwrap().is_empty());
+    }
+}
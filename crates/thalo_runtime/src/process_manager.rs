@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context as AnyhowContext, Result};
+use message_db::database::{MessageStore, WriteMessageOpts};
+use message_db::stream_name::{Category, StreamName};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::module::ModuleName;
+
+const PROCESS_STATE_MSG_TYPE: &str = "Updated";
+
+/// Stores the state of process manager processes in
+/// `<process_manager>:process-<id>` streams.
+///
+/// Each message holds the full state of the process after handling an event,
+/// along with the global position of the last event handled from each
+/// category, so redelivered events are not handled twice.
+#[derive(Clone)]
+pub struct ProcessStore {
+    message_store: MessageStore,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessState {
+    pub module_version: Version,
+    pub positions: BTreeMap<String, i64>,
+    pub state: Value,
+}
+
+/// The latest state of a process, with the version of its stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadedProcess {
+    pub process: ProcessState,
+    pub version: i64,
+}
+
+impl ProcessStore {
+    pub fn new(message_store: MessageStore) -> Self {
+        ProcessStore { message_store }
+    }
+
+    /// Loads the latest state of a process, if it has handled any events.
+    pub async fn load(&self, name: &ModuleName, id: &str) -> Result<Option<LoadedProcess>> {
+        let stream_name = process_stream_name(name, id)?.to_string();
+        let message = MessageStore::get_last_stream_message::<ProcessState, _>(
+            &self.message_store,
+            &stream_name,
+            None,
+        )
+        .await
+        .context("failed to load process state")?;
+
+        Ok(message.map(|message| LoadedProcess {
+            process: message.data,
+            version: message.position,
+        }))
+    }
+
+    /// Saves the state of a process, failing if the process was updated since
+    /// it was loaded at `expected_version`.
+    ///
+    /// Returns the new version of the process stream.
+    pub async fn save(
+        &self,
+        name: &ModuleName,
+        id: &str,
+        process: &ProcessState,
+        expected_version: Option<i64>,
+    ) -> Result<i64> {
+        let stream_name = process_stream_name(name, id)?.to_string();
+        // A process which never handled an event has no stream
+        let opts = WriteMessageOpts::builder()
+            .id(Uuid::new_v4())
+            .expected_version(expected_version.unwrap_or(-1))
+            .build();
+        let version = MessageStore::write_message(
+            &self.message_store,
+            &stream_name,
+            PROCESS_STATE_MSG_TYPE,
+            process,
+            &opts,
+        )
+        .await
+        .context("failed to save process state")?;
+
+        trace!(stream_name = %stream_name, version, "saved process state");
+
+        Ok(version)
+    }
+}
+
+fn process_stream_name(name: &ModuleName, id: &str) -> Result<StreamName> {
+    let category = Category::new(Category::normalize(name), vec!["process".to_string()])?;
+    Ok(StreamName {
+        category,
+        id: Some(id.parse()?),
+    })
+}
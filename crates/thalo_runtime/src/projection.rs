@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context as AnyhowContext, Result};
+use message_db::database::{MessageStore, WriteMessageOpts};
+use message_db::stream_name::{Category, StreamName};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::module::ModuleName;
+
+const ENTRY_SET_MSG_TYPE: &str = "Set";
+const ENTRY_DELETED_MSG_TYPE: &str = "Deleted";
+
+/// Stores the entries of projection tables in `<projection>:entry-<key>`
+/// streams.
+///
+/// Each message holds the value of the entry after handling an event, along
+/// with the global position of the last event applied to it from each
+/// category, so redelivered events are not applied twice.
+#[derive(Clone)]
+pub struct ProjectionStore {
+    message_store: MessageStore,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectionEntry {
+    pub positions: BTreeMap<String, i64>,
+    /// The value of the entry, or `None` if it was deleted.
+    pub value: Option<Value>,
+}
+
+impl ProjectionStore {
+    pub fn new(message_store: MessageStore) -> Self {
+        ProjectionStore { message_store }
+    }
+
+    /// Loads the latest version of an entry, including deleted entries.
+    pub async fn load(&self, name: &ModuleName, key: &str) -> Result<Option<ProjectionEntry>> {
+        let stream_name = entry_stream_name(name, key)?.to_string();
+        let message = MessageStore::get_last_stream_message::<ProjectionEntry, _>(
+            &self.message_store,
+            &stream_name,
+            None,
+        )
+        .await
+        .context("failed to load projection entry")?;
+
+        Ok(message.map(|message| message.data))
+    }
+
+    /// Returns the value of an entry, if it exists.
+    pub async fn get(&self, name: &ModuleName, key: &str) -> Result<Option<Value>> {
+        Ok(self.load(name, key).await?.and_then(|entry| entry.value))
+    }
+
+    pub async fn save(&self, name: &ModuleName, key: &str, entry: &ProjectionEntry) -> Result<()> {
+        let stream_name = entry_stream_name(name, key)?.to_string();
+        let msg_type = match entry.value {
+            Some(_) => ENTRY_SET_MSG_TYPE,
+            None => ENTRY_DELETED_MSG_TYPE,
+        };
+        MessageStore::write_message(
+            &self.message_store,
+            &stream_name,
+            msg_type,
+            entry,
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to save projection entry")?;
+
+        trace!(stream_name = %stream_name, msg_type, "saved projection entry");
+
+        Ok(())
+    }
+}
+
+fn entry_stream_name(name: &ModuleName, key: &str) -> Result<StreamName> {
+    let category = Category::new(Category::normalize(name), vec!["entry".to_string()])?;
+    Ok(StreamName {
+        category,
+        id: Some(key.parse()?),
+    })
+}
@@ -1,53 +1,219 @@
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
-use futures::StreamExt;
-use message_db::database::{MessageStore, SubscribeToCategoryOpts, WriteMessageOpts};
-use message_db::message::MessageData;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::stream::{self, FuturesUnordered};
+use futures::{FutureExt, StreamExt};
+use message_db::database::{
+    GetCategoryMessagesOpts, GetStreamMessagesOpts, MessageStore, SubscribeToCategoryOpts,
+    WriteMessageOpts,
+};
+use message_db::message::{GenericMessage, MessageData, MetadataRef};
 use message_db::stream_name::{Category, StreamName};
-use semver::VersionReq;
+use metrics::{gauge, histogram, increment_counter};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use thalo::Context;
+use thalo::{
+    Context, ScheduleChange, CORRELATION_ID_PROPERTY, TRACEPARENT_PROPERTY, TRACESTATE_PROPERTY,
+};
 use thalo_registry::Registry as RegistryStore;
-use tokio::sync::RwLock;
-use tracing::{error, info, instrument, trace, warn};
-use wasmtime::Engine;
+use thiserror::Error;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info, info_span, instrument, trace, warn, Instrument};
+use uuid::Uuid;
+use wasmtime::component::Component;
+use wasmtime::{Engine, InstanceAllocationStrategy, PoolingAllocationConfig};
 
-use crate::command::CommandRouter;
-use crate::module::{ExecuteResult, Module, ModuleID, ModuleName, SchemaModule};
+use crate::command::{
+    CommandAlreadyHandled, CommandFailed, CommandNotNext, CommandRouter, EntityState,
+    MODULE_VERSION_PROPERTY,
+};
+use crate::compile_cache::CompileCache;
+use crate::config::{Config, ModuleLimits, EPOCH_TICK};
+use crate::dead_letter::{DeadLetter, DeadLetterEntry, DeadLetterQueue};
+use crate::module::process_manager::ProcessManagerModule;
+use crate::module::projection::{Operation, ProjectionModule};
+use crate::module::{
+    check_instance_count, is_trap, EventRef, ExecuteResult, Module, ModuleID, ModuleKind,
+    ModuleName, ResourceLimitExceeded, SchemaModule,
+};
+use crate::outcome::{Outcome, OutcomeStore, OutcomeWaiters};
+use crate::process_manager::{LoadedProcess, ProcessState, ProcessStore};
+use crate::projection::{ProjectionEntry, ProjectionStore};
 use crate::registry::Registry;
+use crate::schedule::{PendingSchedule, PendingSchedules, Schedule, ScheduleOwner, ScheduleStore};
+use crate::snapshot::SnapshotStore;
+use crate::telemetry::{
+    self, COMMANDS_TOTAL, COMMAND_DURATION, MODULE_COMPILE_DURATION, SUBSCRIPTION_LAG,
+};
+
+/// Consumer identifier of the command category subscriptions.
+const SUBSCRIBER_ID: &str = "thalo_runtime";
+/// Postgres error code of a unique constraint violation.
+const UNIQUE_VIOLATION_CODE: &str = "23505";
+/// Interval at which due scheduled commands are submitted.
+const SCHEDULE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of events read per page when no batch size is requested.
+const DEFAULT_READ_BATCH_SIZE: i64 = 100;
+/// Maximum number of events read per page.
+const MAX_READ_BATCH_SIZE: i64 = 1_000;
+/// Maximum number of commands a category subscription handles concurrently.
+const MAX_IN_FLIGHT_COMMANDS: usize = 1_000;
+/// Number of handled messages after which a subscription records its
+/// position.
+pub(crate) const SUBSCRIPTION_POSITION_UPDATE_INTERVAL: u64 = 100;
 
 #[derive(Clone)]
 pub struct Runtime {
     engine: Engine,
+    compile_cache: CompileCache,
     modules: Arc<RwLock<BTreeMap<ModuleID, Arc<Module>>>>,
+    process_managers: Arc<RwLock<BTreeMap<ModuleID, Arc<ProcessManagerModule>>>>,
+    projections: Arc<RwLock<BTreeMap<ModuleID, Arc<ProjectionModule>>>>,
+    module_kinds: Arc<RwLock<HashMap<ModuleName, ModuleKind>>>,
     registry: Arc<RwLock<Registry>>,
+    subscriptions: Arc<Mutex<HashMap<ModuleName, Subscription>>>,
+    shutdown: Arc<watch::Sender<bool>>,
+    version_reqs: Arc<RwLock<HashMap<ModuleName, VersionReq>>>,
+    unhealthy_modules: Arc<RwLock<HashMap<ModuleID, String>>>,
     command_router: CommandRouter,
     message_store: MessageStore,
     registry_store: RegistryStore,
+    snapshot_store: SnapshotStore,
+    dead_letters: DeadLetterQueue,
+    outcomes: OutcomeStore,
+    outcome_waiters: OutcomeWaiters,
+    direct_commands: Arc<Mutex<HashSet<Uuid>>>,
+    processes: ProcessStore,
+    projection_entries: ProjectionStore,
+    schedules: ScheduleStore,
+    config: Arc<Config>,
+}
+
+/// A running category subscription.
+struct Subscription {
+    handle: JoinHandle<()>,
+    /// Stops the subscription once set, after it records its position.
+    stop: watch::Sender<bool>,
+}
+
+/// The runtime is shutting down, and no longer accepts requests.
+#[derive(Debug, Error)]
+#[error("runtime is shutting down")]
+pub struct ShuttingDownError;
+
+/// A command with the same ID has already been submitted.
+#[derive(Debug, Error)]
+#[error("command {0} has already been submitted")]
+pub struct DuplicateCommandError(pub Uuid);
+
+/// A page of events read from a stream or category.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventPage {
+    pub messages: Vec<GenericMessage>,
+    /// Position to read the next page from. Stream positions for streams, and
+    /// global positions for categories.
+    pub next_position: i64,
+    /// Whether the end of the stream or category was reached.
+    pub caught_up: bool,
+}
+
+/// A module version was marked unhealthy after exceeding its resource limits.
+#[derive(Debug, Error)]
+#[error("module '{name}' version {version} is unhealthy: {reason}")]
+pub struct UnhealthyModuleError {
+    pub name: ModuleName,
+    pub version: Version,
+    pub reason: String,
 }
 
 impl Runtime {
-    pub fn new(message_store: MessageStore, registry_store: RegistryStore) -> Self {
-        let mut config = wasmtime::Config::new();
-        config.async_support(true).wasm_component_model(true);
-        let engine = Engine::new(&config).unwrap();
+    pub fn new(message_store: MessageStore, registry_store: RegistryStore, config: Config) -> Self {
+        // Instances are allocated from pre-reserved slots, large enough for the
+        // most permissive module limits
+        let mut pooling = PoolingAllocationConfig::default();
+        pooling
+            .instance_count(config.instance_slots)
+            .instance_memory_pages(
+                config
+                    .all_module_limits()
+                    .map(|limits| limits.max_memory_pages as u64)
+                    .max()
+                    .unwrap_or_default(),
+            )
+            .instance_table_elements(
+                config
+                    .all_module_limits()
+                    .map(|limits| limits.max_table_elements)
+                    .max()
+                    .unwrap_or_default(),
+            );
+        let mut engine_config = wasmtime::Config::new();
+        engine_config
+            .async_support(true)
+            .wasm_component_model(true)
+            .epoch_interruption(true)
+            .allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+        let engine = Engine::new(&engine_config).unwrap();
+
+        // Guests are interrupted once their epoch deadline is reached
+        let ticker_engine = engine.clone();
+        thread::spawn(move || loop {
+            thread::sleep(EPOCH_TICK);
+            ticker_engine.increment_epoch();
+        });
 
         Runtime {
+            compile_cache: CompileCache::new(engine.clone(), config.compile_cache_dir.clone()),
             engine,
             modules: Arc::new(RwLock::new(BTreeMap::new())),
+            process_managers: Arc::new(RwLock::new(BTreeMap::new())),
+            projections: Arc::new(RwLock::new(BTreeMap::new())),
+            module_kinds: Arc::new(RwLock::new(HashMap::new())),
             registry: Arc::new(RwLock::new(Registry::default())),
-            command_router: CommandRouter::start(),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: Arc::new(watch::channel(false).0),
+            version_reqs: Arc::new(RwLock::new(config.module_versions.clone())),
+            unhealthy_modules: Arc::new(RwLock::new(HashMap::new())),
+            command_router: CommandRouter::start(config.router_shards, config.handler_capacity),
+            snapshot_store: SnapshotStore::new(message_store.clone(), config.snapshot_interval),
+            dead_letters: DeadLetterQueue::new(message_store.clone()),
+            outcomes: OutcomeStore::new(message_store.clone()),
+            outcome_waiters: OutcomeWaiters::default(),
+            direct_commands: Arc::new(Mutex::new(HashSet::new())),
+            processes: ProcessStore::new(message_store.clone()),
+            projection_entries: ProjectionStore::new(message_store.clone()),
+            schedules: ScheduleStore::new(message_store.clone()),
             message_store,
             registry_store,
+            config: Arc::new(config),
         }
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn message_store(&self) -> &MessageStore {
         &self.message_store
     }
 
+    pub fn snapshot_store(&self) -> &SnapshotStore {
+        &self.snapshot_store
+    }
+
+    pub fn outcome_store(&self) -> &OutcomeStore {
+        &self.outcomes
+    }
+
     pub async fn init(&self) -> Result<()> {
         let modules = self
             .registry_store
@@ -55,7 +221,28 @@ impl Runtime {
             .await
             .context("failed to load all modules from registry")?;
         for module in modules {
+            // Compiling ahead of time keeps the first commands after a restart fast
+            if let Err(err) = self.compile_cache.precompile(&module.module).await {
+                warn!(
+                    module_name = %module.name,
+                    version = %module.version,
+                    "failed to precompile module: {err}"
+                );
+            }
+
             let module_id = ModuleID::new(module.name.parse()?, module.version);
+            if !self.module_kinds.read().await.contains_key(&module_id.name) {
+                let kind = self
+                    .detect_module_kind(&module_id, &module.module)
+                    .await
+                    .with_context(|| {
+                        format!("failed to detect kind of module '{}'", module.name)
+                    })?;
+                self.module_kinds
+                    .write()
+                    .await
+                    .insert(module_id.name.clone(), kind);
+            }
             let mut registry = self.registry.write().await;
             registry.add_module(module_id, module.module);
         }
@@ -64,34 +251,125 @@ impl Runtime {
     }
 
     pub async fn start(&self) {
+        let module_kinds = self.module_kinds.read().await.clone();
         for module_name in self.registry.read().await.modules.keys().cloned() {
-            self.start_module(module_name);
+            match module_kinds.get(&module_name) {
+                Some(kind @ (ModuleKind::ProcessManager | ModuleKind::Projection)) => {
+                    self.start_event_subscription(module_name, *kind)
+                }
+                _ => self.start_module(module_name),
+            }
         }
+        self.start_scheduler();
     }
 
+    /// Subscribes to the module's command category, unless already
+    /// subscribed.
+    ///
+    /// Commands are dispatched to their entity's handler without waiting for
+    /// the result, so commands of different entities are handled concurrently,
+    /// up to [`MAX_IN_FLIGHT_COMMANDS`]. The recorded position only advances
+    /// past commands once they and every command before them have completed.
+    ///
+    /// The subscription stops once the runtime shuts down, waiting for the
+    /// commands being handled before recording its position.
+    ///
+    /// Members of a consumer group only receive commands of the entity streams
+    /// assigned to them.
     fn start_module(&self, module_name: ModuleName) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.contains_key(&module_name) {
+            return;
+        }
+
+        let subscription_name = module_name.clone();
         let runtime = self.clone();
+        let (stop_tx, mut stop) = watch::channel(*self.shutdown.borrow());
         let category_name = format!("{}:command", Category::normalize(&module_name.to_string()));
 
         trace!("subscribing to category '{category_name}'");
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
+            let subscriber_id = runtime.subscriber_id();
+            let last_position = match runtime
+                .load_subscription_position(&category_name, &subscriber_id)
+                .await
+            {
+                Ok(last_position) => last_position.unwrap_or(0),
+                Err(err) => {
+                    error!(stream = %category_name, "failed to subscribe to category: {err}");
+                    return;
+                }
+            };
+            let opts = match runtime.config.consumer_group {
+                Some(group) => SubscribeToCategoryOpts::builder()
+                    .position(last_position + 1)
+                    .consumer_group_member(i64::from(group.member))
+                    .consumer_group_size(i64::from(group.size))
+                    .build(),
+                None => SubscribeToCategoryOpts::builder()
+                    .position(last_position + 1)
+                    .build(),
+            };
             let mut stream = MessageStore::subscribe_to_category::<MessageData, _>(
                 &runtime.message_store,
                 &category_name,
-                &SubscribeToCategoryOpts::builder()
-                    .identifier("thalo_runtime")
-                    .build(),
+                &opts,
             )
             .await
             .unwrap();
-            while let Some(batch) = stream.next().await {
+            let mut positions = CommandPositions::new(last_position);
+            let mut in_flight = FuturesUnordered::new();
+            'subscription: loop {
+                if *stop.borrow() {
+                    break;
+                }
+                let batch = tokio::select! {
+                    batch = stream.next() => match batch {
+                        Some(batch) => batch,
+                        None => break,
+                    },
+                    Some(position) = in_flight.next(), if !in_flight.is_empty() => {
+                        let recorded = positions.complete(position);
+                        positions.record_lag(&category_name);
+                        if let Some(position) = recorded {
+                            runtime
+                                .record_command_position(&category_name, &subscriber_id, position)
+                                .await;
+                        }
+                        continue;
+                    }
+                    _ = stop.changed() => break,
+                };
+
                 match batch {
                     Ok(commands) => {
                         for command in commands {
+                            if *stop.borrow() {
+                                break 'subscription;
+                            }
+                            if in_flight.len() >= MAX_IN_FLIGHT_COMMANDS {
+                                if let Some(position) = in_flight.next().await {
+                                    let recorded = positions.complete(position);
+                                    positions.record_lag(&category_name);
+                                    if let Some(position) = recorded {
+                                        runtime
+                                            .record_command_position(
+                                                &category_name,
+                                                &subscriber_id,
+                                                position,
+                                            )
+                                            .await;
+                                    }
+                                }
+                            }
+
+                            let global_position = command.global_position;
+
                             let id = match &command.stream_name.id {
                                 Some(id) => id.cardinal_id().to_string(),
                                 None => {
                                     warn!(command_id = ?command.id, "missing id from command");
+                                    positions.skip(global_position);
                                     continue;
                                 }
                             };
@@ -104,24 +382,40 @@ impl Runtime {
                                 "handling command"
                             );
 
-                            let ctx = Context {
-                                id: command.id,
-                                stream_name: command.stream_name,
-                                position: command.position,
-                                global_position: command.global_position,
-                                metadata: command.metadata,
-                                time: command.time,
-                            };
+                            let command_type = command.msg_type.clone();
+                            let data = command.data.clone();
+                            let ctx = message_context(command);
 
-                            let _ = runtime
-                                .execute(
+                            // Continues the trace of the command's submitter
+                            let span = info_span!("handle_command", command_id = %ctx.id);
+                            if let Some(trace_context) = ctx.trace_context() {
+                                telemetry::set_parent(&span, &trace_context);
+                            }
+                            // Queued in order, so commands of an entity are still handled in order
+                            let result = runtime
+                                .queue_command(
                                     module_name.clone(),
                                     id,
-                                    ctx,
-                                    command.msg_type.clone(),
-                                    command.data,
+                                    ctx.clone(),
+                                    command_type.clone(),
+                                    data.clone(),
+                                    false,
                                 )
+                                .instrument(span.clone())
                                 .await;
+                            positions.start(global_position, ctx.time);
+                            positions.record_lag(&category_name);
+                            let runtime = runtime.clone();
+                            in_flight.push(
+                                async move {
+                                    let result = result.await;
+                                    runtime
+                                        .complete_command(ctx, command_type, data, result)
+                                        .await;
+                                    global_position
+                                }
+                                .instrument(span),
+                            );
                         }
                     }
                     Err(err) => {
@@ -129,9 +423,808 @@ impl Runtime {
                     }
                 }
             }
+
+            while let Some(position) = in_flight.next().await {
+                positions.complete(position);
+            }
+            positions.record_lag(&category_name);
+            if let Some(position) = positions.unrecorded() {
+                runtime
+                    .record_command_position(&category_name, &subscriber_id, position)
+                    .await;
+            }
+            trace!(stream = %category_name, "unsubscribed from category");
+        });
+        subscriptions.insert(
+            subscription_name,
+            Subscription {
+                handle,
+                stop: stop_tx,
+            },
+        );
+    }
+
+    /// Records the position of a command subscription, logging failures since
+    /// the position is recorded again later.
+    async fn record_command_position(
+        &self,
+        category_name: &str,
+        subscriber_id: &str,
+        position: i64,
+    ) {
+        if let Err(err) = self
+            .flush_subscription_position(category_name, subscriber_id, position)
+            .await
+        {
+            error!(stream = %category_name, "failed to flush subscription position: {err}");
+        }
+    }
+
+    /// Loads the last position recorded for a category subscription.
+    async fn load_subscription_position(
+        &self,
+        category_name: &str,
+        subscriber_id: &str,
+    ) -> Result<Option<i64>> {
+        let message = MessageStore::get_last_stream_message::<MessageData, _>(
+            &self.message_store,
+            &format!("{category_name}+position-{subscriber_id}"),
+            None,
+        )
+        .await
+        .context("failed to load subscription position")?;
+
+        Ok(message.and_then(|message| message.data.get("position").and_then(Value::as_i64)))
+    }
+
+    /// Records the position of a category subscription, so it resumes after
+    /// the last handled command when restarted.
+    ///
+    /// Positions are written to the subscription's `<category>+position-<id>`
+    /// stream, as done periodically by the subscription itself.
+    async fn flush_subscription_position(
+        &self,
+        category_name: &str,
+        subscriber_id: &str,
+        position: i64,
+    ) -> Result<()> {
+        MessageStore::write_message(
+            &self.message_store,
+            &format!("{category_name}+position-{subscriber_id}"),
+            "Recorded",
+            &serde_json::json!({ "position": position }),
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to write subscription position")?;
+        trace!(stream = %category_name, position, "flushed subscription position");
+
+        Ok(())
+    }
+
+    /// Subscribes to the event categories of a process manager or projection,
+    /// unless already subscribed.
+    ///
+    /// Events are handled one at a time, in order of their category. Only the
+    /// first member of a consumer group handles events, so processes and
+    /// projection entries are never updated concurrently.
+    fn start_event_subscription(&self, module_name: ModuleName, kind: ModuleKind) {
+        if matches!(self.config.consumer_group, Some(group) if group.member != 0) {
+            return;
+        }
+
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.contains_key(&module_name) {
+            return;
+        }
+
+        let subscription_name = module_name.clone();
+        let runtime = self.clone();
+        let (stop_tx, mut stop) = watch::channel(*self.shutdown.borrow());
+        let handle = tokio::spawn(async move {
+            let version_req = runtime.version_req(&module_name).await;
+            let categories = match runtime
+                .event_categories(&module_name, kind, &version_req)
+                .await
+            {
+                Ok(categories) => categories,
+                Err(err) => {
+                    error!(%module_name, "failed to start {kind}: {err}");
+                    return;
+                }
+            };
+
+            // Each module tracks its own position in the categories
+            let subscriber_id = format!("{SUBSCRIBER_ID}_{module_name}");
+            let opts = SubscribeToCategoryOpts::builder()
+                .identifier(subscriber_id.as_str())
+                .build();
+            let mut streams = Vec::with_capacity(categories.len());
+            for category_name in &categories {
+                trace!("subscribing to category '{category_name}'");
+                let stream = MessageStore::subscribe_to_category::<MessageData, _>(
+                    &runtime.message_store,
+                    category_name,
+                    &opts,
+                )
+                .await
+                .unwrap();
+                streams.push(stream.boxed());
+            }
+            let mut stream = stream::select_all(streams);
+
+            let mut last_positions: HashMap<String, i64> = HashMap::new();
+            'subscription: loop {
+                if *stop.borrow() {
+                    break;
+                }
+                let batch = tokio::select! {
+                    batch = stream.next() => match batch {
+                        Some(batch) => batch,
+                        None => break,
+                    },
+                    _ = stop.changed() => break,
+                };
+
+                match batch {
+                    Ok(events) => {
+                        for event in events {
+                            if *stop.borrow() {
+                                break 'subscription;
+                            }
+                            let event_type = event.msg_type.clone();
+                            let data = event.data.clone();
+                            let ctx = message_context(event);
+
+                            // Continues the trace of the command causing the event
+                            let span = info_span!("handle_event", %module_name, event_id = %ctx.id);
+                            if let Some(trace_context) = ctx.trace_context() {
+                                telemetry::set_parent(&span, &trace_context);
+                            }
+                            if let Err(err) = runtime
+                                .handle_event(
+                                    &module_name,
+                                    kind,
+                                    &version_req,
+                                    &ctx,
+                                    &event_type,
+                                    &data,
+                                )
+                                .instrument(span)
+                                .await
+                            {
+                                error!(
+                                    %module_name,
+                                    event_id = %ctx.id,
+                                    "failed to handle event: {err}"
+                                );
+                            }
+                            last_positions
+                                .insert(ctx.stream_name.category.to_string(), ctx.global_position);
+                        }
+                    }
+                    Err(err) => {
+                        error!(%module_name, "failed to read event: {err}");
+                    }
+                }
+            }
+
+            for (category_name, position) in last_positions {
+                if let Err(err) = runtime
+                    .flush_subscription_position(&category_name, &subscriber_id, position)
+                    .await
+                {
+                    error!(stream = %category_name, "failed to flush subscription position: {err}");
+                }
+            }
+            trace!(%module_name, "unsubscribed from event categories");
+        });
+        subscriptions.insert(
+            subscription_name,
+            Subscription {
+                handle,
+                stop: stop_tx,
+            },
+        );
+    }
+
+    /// Stops the event subscriptions of a process manager or projection and
+    /// starts them again, picking up the categories of the latest module
+    /// version.
+    ///
+    /// The subscriptions finish handling their current event and record their
+    /// positions before being restarted, so no events are redelivered.
+    async fn restart_event_subscription(&self, module_name: ModuleName, kind: ModuleKind) {
+        let subscription = self.subscriptions.lock().unwrap().remove(&module_name);
+        if let Some(subscription) = subscription {
+            subscription.stop.send_replace(true);
+            let _ = subscription.handle.await;
+        }
+        self.start_event_subscription(module_name, kind);
+    }
+
+    /// Returns the event categories a process manager or projection
+    /// subscribes to.
+    async fn event_categories(
+        &self,
+        name: &ModuleName,
+        kind: ModuleKind,
+        version_req: &VersionReq,
+    ) -> Result<Vec<String>> {
+        match kind {
+            ModuleKind::Aggregate => bail!("aggregates do not subscribe to events"),
+            ModuleKind::ProcessManager => {
+                let (_, process_manager) = self.load_process_manager(name, version_req).await?;
+                process_manager.categories().await
+            }
+            ModuleKind::Projection => {
+                let (_, projection) = self.load_projection(name, version_req).await?;
+                projection.categories().await
+            }
+        }
+    }
+
+    async fn handle_event(
+        &self,
+        name: &ModuleName,
+        kind: ModuleKind,
+        version_req: &VersionReq,
+        ctx: &Context,
+        event_type: &str,
+        data: &Value,
+    ) -> Result<()> {
+        match kind {
+            ModuleKind::Aggregate => bail!("aggregates do not subscribe to events"),
+            ModuleKind::ProcessManager => {
+                self.handle_process_event(name, version_req, ctx, event_type, data)
+                    .await
+            }
+            ModuleKind::Projection => {
+                self.handle_projection_event(name, version_req, ctx, event_type, data)
+                    .await
+            }
+        }
+    }
+
+    /// Routes an event to its process, and handles it with the process's
+    /// state.
+    ///
+    /// Commands emitted by the process are submitted before its state is
+    /// saved, with IDs derived from the event, so handling an event again
+    /// after a failure never submits the same command twice.
+    async fn handle_process_event(
+        &self,
+        name: &ModuleName,
+        version_req: &VersionReq,
+        ctx: &Context,
+        event_type: &str,
+        data: &Value,
+    ) -> Result<()> {
+        let (module_id, process_manager) = self.load_process_manager(name, version_req).await?;
+        let category_name = ctx.stream_name.category.to_string();
+        let payload = serde_json::to_vec(data)?;
+        let event = EventRef {
+            ctx,
+            event_type,
+            payload: &payload,
+        };
+
+        let result = async {
+            let Some(process_id) = process_manager.route(event).await? else {
+                trace!(event_id = %ctx.id, "event not routed to a process");
+                return Ok(());
+            };
+
+            let (mut positions, state, version) =
+                match self.processes.load(name, &process_id).await? {
+                    Some(LoadedProcess { process, version }) => {
+                        if process
+                            .positions
+                            .get(&category_name)
+                            .map_or(false, |position| *position >= ctx.global_position)
+                        {
+                            trace!(event_id = %ctx.id, %process_id, "event already handled");
+                            return Ok(());
+                        }
+                        (
+                            process.positions,
+                            serde_json::to_vec(&process.state)?,
+                            Some(version),
+                        )
+                    }
+                    None => (
+                        BTreeMap::new(),
+                        process_manager.init(&process_id).await?,
+                        None,
+                    ),
+                };
+
+            let output = process_manager.handle(&state, event).await?;
+            for (i, command) in output.commands.iter().enumerate() {
+                let aggregate: ModuleName = command.aggregate.parse()?;
+                let command_id = Uuid::new_v5(&ctx.id, format!("{name}/{i}").as_bytes());
+                let data: Value = serde_json::from_slice(&command.payload)
+                    .context("failed to deserialize command payload")?;
+                match self
+                    .submit_command(
+                        &aggregate,
+                        &command.id,
+                        Some(command_id),
+                        &command.command,
+                        &data,
+                        None,
+                        ctx.correlation_id(),
+                    )
+                    .await
+                {
+                    Ok(_) => {
+                        trace!(%command_id, %aggregate, "submitted process command");
+                    }
+                    Err(err) if err.is::<DuplicateCommandError>() => {
+                        trace!(%command_id, %aggregate, "process command already submitted");
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            positions.insert(category_name, ctx.global_position);
+            let process = ProcessState {
+                module_version: module_id.version.clone(),
+                positions,
+                state: serde_json::from_slice(&output.state)
+                    .context("failed to deserialize process state")?,
+            };
+            self.processes
+                .save(name, &process_id, &process, version)
+                .await?;
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if let Err(err) = &result {
+            if is_trap(err) {
+                self.record_trap(&module_id, err).await;
+            }
+        }
+
+        result
+    }
+
+    /// Applies an event to the entries of a projection's table.
+    ///
+    /// Entries are loaded for the keys returned by the projection, and only
+    /// changed if they haven't already been updated by the event.
+    async fn handle_projection_event(
+        &self,
+        name: &ModuleName,
+        version_req: &VersionReq,
+        ctx: &Context,
+        event_type: &str,
+        data: &Value,
+    ) -> Result<()> {
+        let (module_id, projection) = self.load_projection(name, version_req).await?;
+        let category_name = ctx.stream_name.category.to_string();
+        let payload = serde_json::to_vec(data)?;
+        let event = EventRef {
+            ctx,
+            event_type,
+            payload: &payload,
+        };
+        let handled = |entry: &ProjectionEntry| {
+            entry
+                .positions
+                .get(&category_name)
+                .map_or(false, |position| *position >= ctx.global_position)
+        };
+
+        let result = async {
+            let keys = projection.keys(event).await?;
+            let mut loaded = HashMap::with_capacity(keys.len());
+            for key in keys {
+                let entry = self.projection_entries.load(name, &key).await?;
+                loaded.insert(key, entry.unwrap_or_default());
+            }
+            if !loaded.is_empty() && loaded.values().all(handled) {
+                trace!(event_id = %ctx.id, "event already handled");
+                return Ok(());
+            }
+
+            let entries = loaded
+                .iter()
+                .filter_map(|(key, entry)| {
+                    let value = entry.value.as_ref()?;
+                    Some(serde_json::to_vec(value).map(|value| (key.clone(), value)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let operations = projection.handle(event, &entries).await?;
+            for operation in operations {
+                let (key, value) = match operation {
+                    Operation::Set { key, value } => {
+                        let value = serde_json::from_slice(&value)
+                            .context("failed to deserialize projection entry")?;
+                        (key, Some(value))
+                    }
+                    Operation::Delete { key } => (key, None),
+                };
+                // Entries written without being loaded still need their positions
+                let mut entry = match loaded.remove(&key) {
+                    Some(entry) => entry,
+                    None => self
+                        .projection_entries
+                        .load(name, &key)
+                        .await?
+                        .unwrap_or_default(),
+                };
+                if handled(&entry) {
+                    trace!(event_id = %ctx.id, %key, "entry already updated by event");
+                    continue;
+                }
+                entry
+                    .positions
+                    .insert(category_name.clone(), ctx.global_position);
+                entry.value = value;
+                self.projection_entries.save(name, &key, &entry).await?;
+            }
+
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if let Err(err) = &result {
+            if is_trap(err) {
+                self.record_trap(&module_id, err).await;
+            }
+        }
+
+        result
+    }
+
+    /// Returns the value of an entry in a projection's table.
+    pub async fn query_projection(&self, name: &ModuleName, key: &str) -> Result<Option<Value>> {
+        if self.module_kinds.read().await.get(name) != Some(&ModuleKind::Projection) {
+            bail!("module '{name}' is not a projection");
+        }
+
+        self.projection_entries.get(name, key).await
+    }
+
+    /// Submits scheduled commands once they come due, until the runtime shuts
+    /// down.
+    ///
+    /// Only the first member of a consumer group submits scheduled commands.
+    /// Pending schedules are read from the message store on start, so they
+    /// survive restarts.
+    fn start_scheduler(&self) {
+        if matches!(self.config.consumer_group, Some(group) if group.member != 0) {
+            return;
+        }
+
+        let runtime = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut pending = PendingSchedules::new(runtime.schedules.clone());
+            let mut interval = time::interval(SCHEDULE_POLL_INTERVAL);
+            loop {
+                if *shutdown.borrow() {
+                    break;
+                }
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+
+                if let Err(err) = pending.refresh().await {
+                    error!("failed to read schedules: {err}");
+                    continue;
+                }
+                for schedule in pending.take_due(Utc::now()) {
+                    if let Err(err) = runtime.submit_schedule(&schedule).await {
+                        error!(schedule_id = %schedule.id, "failed to submit scheduled command: {err}");
+                        pending.insert(schedule);
+                    }
+                }
+            }
+            trace!("stopped scheduler");
         });
     }
 
+    /// Submits a due scheduled command, using the schedule's message ID as the
+    /// command ID so it's only submitted once.
+    async fn submit_schedule(&self, pending: &PendingSchedule) -> Result<()> {
+        let schedule = &pending.schedule;
+        let command_id = pending.message_id;
+        match self
+            .submit_command(
+                &schedule.aggregate,
+                &schedule.entity_id,
+                Some(command_id),
+                &schedule.command,
+                &schedule.payload,
+                None,
+                schedule.correlation_id.as_deref(),
+            )
+            .await
+        {
+            Ok(_) => {}
+            Err(err) if err.is::<DuplicateCommandError>() => {
+                trace!(schedule_id = %pending.id, "scheduled command already submitted");
+            }
+            Err(err) => return Err(err),
+        }
+        info!(schedule_id = %pending.id, %command_id, "submitted scheduled command");
+
+        if let Err(err) = self.schedules.submitted(pending, command_id).await {
+            // The schedule was replaced or cancelled after being submitted
+            warn!(schedule_id = %pending.id, "failed to mark schedule as submitted: {err}");
+        }
+
+        Ok(())
+    }
+
+    /// Saves the changes to scheduled commands requested while handling a
+    /// command.
+    pub async fn save_schedule_changes(
+        &self,
+        ctx: &Context,
+        changes: &[ScheduleChange],
+    ) -> Result<()> {
+        let entity_id = ctx
+            .stream_name
+            .id
+            .as_ref()
+            .map(|id| id.cardinal_id().to_string())
+            .ok_or_else(|| anyhow!("missing id from command stream"))?;
+        let owner = ScheduleOwner {
+            entity_name: &ctx.stream_name.category.entity_name,
+            entity_id: &entity_id,
+        };
+        for change in changes {
+            match change {
+                ScheduleChange::Schedule(scheduled) => {
+                    let schedule = Schedule {
+                        aggregate: scheduled.aggregate.parse()?,
+                        entity_id: scheduled.entity_id.clone(),
+                        command: scheduled.command.clone(),
+                        payload: serde_json::from_slice(&scheduled.payload)
+                            .context("scheduled command payload is not valid json")?,
+                        due: scheduled.due,
+                        correlation_id: ctx.correlation_id().map(str::to_string),
+                    };
+                    self.schedules
+                        .schedule(&owner, &scheduled.id, &schedule)
+                        .await?;
+                }
+                ScheduleChange::Cancel(id) => {
+                    if !self.schedules.cancel(&owner, id).await? {
+                        trace!(schedule_id = %id, "schedule to cancel is not pending");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Cancels a pending scheduled command by the aggregate instance which
+    /// scheduled it and its schedule ID.
+    pub async fn cancel_schedule(
+        &self,
+        name: &ModuleName,
+        entity_id: &str,
+        id: &str,
+    ) -> Result<()> {
+        let entity_name = Category::normalize(name);
+        let owner = ScheduleOwner {
+            entity_name: &entity_name,
+            entity_id,
+        };
+        if !self.schedules.cancel(&owner, id).await? {
+            bail!("schedule '{id}' of {name} '{entity_id}' is not pending");
+        }
+
+        Ok(())
+    }
+
+    /// Reads a page of events from an entity's event stream, starting at a
+    /// stream position.
+    ///
+    /// Events not matching any of the event types are skipped, unless no
+    /// event types are given.
+    pub async fn read_stream(
+        &self,
+        name: &ModuleName,
+        id: &str,
+        position: i64,
+        batch_size: Option<i64>,
+        event_types: &[String],
+    ) -> Result<EventPage> {
+        let stream_name = event_stream_name(name, id)?;
+        let batch_size = read_batch_size(batch_size);
+        let opts = GetStreamMessagesOpts::builder()
+            .position(position)
+            .batch_size(batch_size)
+            .build();
+        let messages = MessageStore::get_stream_messages::<MessageData, _>(
+            &self.message_store,
+            &stream_name.to_string(),
+            &opts,
+        )
+        .await?;
+        let next_position = messages
+            .last()
+            .map_or(position, |message| message.position + 1);
+
+        Ok(event_page(messages, next_position, batch_size, event_types))
+    }
+
+    /// Reads a page of events from an aggregate's event category, starting at
+    /// a global position.
+    ///
+    /// Events not matching any of the event types are skipped, unless no
+    /// event types are given.
+    pub async fn read_category(
+        &self,
+        name: &ModuleName,
+        position: i64,
+        batch_size: Option<i64>,
+        event_types: &[String],
+    ) -> Result<EventPage> {
+        let category = Category::normalize(name);
+        let batch_size = read_batch_size(batch_size);
+        let messages = self
+            .get_category_messages(&category, position, batch_size)
+            .await?;
+        let next_position = messages
+            .last()
+            .map_or(position, |message| message.global_position + 1);
+
+        Ok(event_page(messages, next_position, batch_size, event_types))
+    }
+
+    /// Returns the global position a subscription to a category starts from.
+    ///
+    /// Without an explicit position, subscriptions with a consumer ID resume
+    /// after the last position recorded for the consumer.
+    pub async fn subscription_start_position(
+        &self,
+        category: &str,
+        position: Option<i64>,
+        consumer_id: Option<&str>,
+    ) -> Result<i64> {
+        if let Some(consumer_id) = consumer_id {
+            if consumer_id.starts_with(SUBSCRIBER_ID) {
+                bail!("consumer id '{consumer_id}' is reserved by the runtime");
+            }
+        }
+        if let Some(position) = position {
+            return Ok(position);
+        }
+        let consumer_id = match consumer_id {
+            Some(consumer_id) => consumer_id,
+            None => return Ok(1),
+        };
+        let last_position = self
+            .load_subscription_position(category, consumer_id)
+            .await?;
+
+        Ok(last_position.map_or(1, |position| position + 1))
+    }
+
+    /// Reads the next batch of messages for a subscription to a category.
+    pub async fn read_subscription_batch(
+        &self,
+        category: &str,
+        position: i64,
+    ) -> Result<Vec<GenericMessage>> {
+        self.get_category_messages(category, position, DEFAULT_READ_BATCH_SIZE)
+            .await
+    }
+
+    /// Records the last position acknowledged by a subscription's consumer.
+    pub async fn record_subscription_position(
+        &self,
+        category: &str,
+        consumer_id: &str,
+        position: i64,
+    ) -> Result<()> {
+        self.flush_subscription_position(category, consumer_id, position)
+            .await
+    }
+
+    async fn get_category_messages(
+        &self,
+        category: &str,
+        position: i64,
+        batch_size: i64,
+    ) -> Result<Vec<GenericMessage>> {
+        let opts = GetCategoryMessagesOpts::builder()
+            .position(position)
+            .batch_size(batch_size)
+            .build();
+        let messages = MessageStore::get_category_messages::<MessageData, _>(
+            &self.message_store,
+            category,
+            &opts,
+        )
+        .await?;
+
+        Ok(messages)
+    }
+
+    /// Returns the consumer identifier of the command category subscriptions.
+    ///
+    /// Each consumer group member tracks its own position.
+    fn subscriber_id(&self) -> String {
+        match self.config.consumer_group {
+            Some(group) => format!("{SUBSCRIBER_ID}_{}", group.member),
+            None => SUBSCRIBER_ID.to_string(),
+        }
+    }
+
+    /// Returns whether this node handles an entity stream, which is always the
+    /// case outside of a consumer group.
+    fn owns_stream(&self, stream_name: &StreamName) -> bool {
+        match (self.config.consumer_group, &stream_name.id) {
+            (Some(group), Some(id)) => group.owns(&id.cardinal_id().to_string()),
+            _ => true,
+        }
+    }
+
+    /// Returns whether the runtime started shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Returns a receiver notified once the runtime starts shutting down.
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Gracefully shuts down the runtime.
+    ///
+    /// Category subscriptions are stopped and their positions flushed, before
+    /// waiting for queued commands to be handled. Gives up once the configured
+    /// shutdown timeout is exceeded.
+    pub async fn shutdown(&self) -> Result<()> {
+        info!("shutting down runtime");
+        self.shutdown.send_replace(true);
+
+        let subscriptions: Vec<_> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, subscription)| subscription)
+            .collect();
+        for subscription in &subscriptions {
+            subscription.stop.send_replace(true);
+        }
+        let drain = async {
+            for subscription in subscriptions {
+                let _ = subscription.handle.await;
+            }
+            self.command_router.shutdown().await;
+        };
+
+        time::timeout(self.config.shutdown_timeout, drain)
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "queued commands were not handled within {:?}",
+                    self.config.shutdown_timeout
+                )
+            })?;
+        info!("runtime shut down");
+
+        Ok(())
+    }
+
+    /// Executes a command directly with its entity's handler.
+    ///
+    /// A command from the entity's command stream is only executed if every
+    /// earlier command of the stream was handled, failing with
+    /// [`CommandNotNext`] otherwise.
     #[instrument(skip(self, ctx, payload))]
     pub async fn execute(
         &self,
@@ -141,9 +1234,30 @@ impl Runtime {
         command: String,
         payload: Value,
     ) -> Result<ExecuteResult> {
-        let result = self
+        self.queue_command(name, id, ctx, command, payload, true)
+            .await
+            .await
+    }
+
+    /// Queues a command with its entity's handler, returning a future of its
+    /// result once queued.
+    ///
+    /// Commands queued one after another are handled in order.
+    async fn queue_command(
+        &self,
+        name: ModuleName,
+        id: String,
+        ctx: Context,
+        command: String,
+        payload: Value,
+        direct: bool,
+    ) -> BoxFuture<'static, Result<ExecuteResult>> {
+        let aggregate_label = name.to_string();
+        let command_label = command.clone();
+        let start = Instant::now();
+        let queued = self
             .command_router
-            .execute(
+            .submit(
                 self.clone(),
                 self.message_store.clone(),
                 name,
@@ -151,50 +1265,475 @@ impl Runtime {
                 ctx,
                 command,
                 payload,
+                direct,
             )
             .await;
 
-        match &result {
-            Ok(ExecuteResult::Events(events)) => {
-                info!("{} events saved", events.len());
-            }
-            Ok(ExecuteResult::Ignored(_)) => {}
-            Err(err) => {
-                error!("command failed: {err}");
-            }
+        async move {
+            let result = match queued {
+                Ok(rx) => rx
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("command handler stopped"))),
+                Err(err) => Err(err),
+            };
+            histogram!(
+                COMMAND_DURATION,
+                start.elapsed().as_secs_f64(),
+                "aggregate" => aggregate_label.clone(),
+                "command" => command_label.clone()
+            );
+
+            let outcome = match &result {
+                Ok(ExecuteResult::Events(events)) => {
+                    info!("{} events saved", events.len());
+                    "accepted"
+                }
+                Ok(ExecuteResult::Ignored(_)) => "ignored",
+                Err(err) => {
+                    error!("command failed: {err}");
+                    "failed"
+                }
+            };
+            increment_counter!(
+                COMMANDS_TOTAL,
+                "aggregate" => aggregate_label,
+                "command" => command_label,
+                "outcome" => outcome
+            );
+
+            result
         }
+        .in_current_span()
+        .boxed()
+    }
 
-        result
+    /// Returns the current state of an entity, or its state at a position of
+    /// its stream if `at_position` is set.
+    pub async fn get_state(
+        &self,
+        name: ModuleName,
+        id: String,
+        at_position: Option<i64>,
+    ) -> Result<EntityState> {
+        if let Some(kind @ (ModuleKind::ProcessManager | ModuleKind::Projection)) =
+            self.module_kinds.read().await.get(&name)
+        {
+            bail!("module '{name}' is a {kind}, not an aggregate");
+        }
+
+        self.command_router
+            .get_state(
+                self.clone(),
+                self.message_store.clone(),
+                name,
+                id,
+                at_position,
+            )
+            .await
     }
 
+    /// Writes a command to the entity's command stream, to be executed by the
+    /// command stream subscription.
+    ///
+    /// If a command ID is provided, it is used as the message ID. Submitting
+    /// the same command ID twice returns a [`DuplicateCommandError`].
+    ///
+    /// A module version requirement can be provided to handle the command
+    /// with a specific module version range, instead of the module's pinned
+    /// version requirement.
+    ///
+    /// The command continues the given correlation, or starts a new one
+    /// identified by the command ID. The trace context of the current span is
+    /// propagated to the command.
+    #[allow(clippy::too_many_arguments)]
     pub async fn submit_command(
         &self,
         name: &ModuleName,
         id: &str,
+        command_id: Option<Uuid>,
         command: &str,
         data: &Value,
+        module_version: Option<&VersionReq>,
+        correlation_id: Option<&str>,
     ) -> Result<i64> {
-        let category = Category::new(Category::normalize(name), vec!["command".to_string()])?;
-        let stream_name = StreamName {
-            category,
-            id: Some(id.parse()?),
+        let stream_name = command_stream_name(name, id)?;
+        let command_id = command_id.unwrap_or_else(Uuid::new_v4);
+        self.write_command(
+            &stream_name,
+            command_id,
+            command,
+            data,
+            module_version,
+            correlation_id,
+        )
+        .await
+    }
+
+    /// Submits a command and executes it, returning its outcome.
+    ///
+    /// The command is written to the entity's command stream for auditing,
+    /// then executed directly if the entity is assigned to this node. If
+    /// earlier commands of the stream are still to be handled, it's left to the
+    /// command subscription, and its outcome awaited through a waiter
+    /// registered before the command is written.
+    ///
+    /// If the entity is assigned to another node, the command is left to that
+    /// node's subscription, and `None` is returned.
+    ///
+    /// If the command was already submitted, its recorded outcome is returned
+    /// instead, or awaited if not handled yet.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn submit_and_execute(
+        &self,
+        name: &ModuleName,
+        id: &str,
+        command_id: Uuid,
+        command: &str,
+        data: &Value,
+        module_version: Option<&VersionReq>,
+        correlation_id: Option<&str>,
+    ) -> Result<Option<Outcome>> {
+        let stream_name = command_stream_name(name, id)?;
+        let owned = self.owns_stream(&stream_name);
+        let mut waiter = owned.then(|| self.outcome_waiters.wait(command_id));
+        let position = match self
+            .write_command(
+                &stream_name,
+                command_id,
+                command,
+                data,
+                module_version,
+                correlation_id,
+            )
+            .await
+        {
+            Ok(position) => position,
+            Err(err) if err.is::<DuplicateCommandError>() => {
+                // The command is not executed again
+                info!(%command_id, "duplicate command submitted");
+                if let Some(outcome) = self.outcomes.find(&stream_name, command_id).await? {
+                    return Ok(Some(outcome));
+                }
+                return match &mut waiter {
+                    Some(waiter) => waiter.outcome().await.map(Some),
+                    None => Ok(None),
+                };
+            }
+            Err(err) => return Err(err),
+        };
+        let mut waiter = match waiter {
+            Some(waiter) => waiter,
+            None => return Ok(None),
         };
 
+        // The command is read back for its global position and time
+        let message = MessageStore::get_stream_messages::<MessageData, _>(
+            &self.message_store,
+            &stream_name.to_string(),
+            &GetStreamMessagesOpts::builder()
+                .position(position)
+                .batch_size(1)
+                .build(),
+        )
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("submitted command {command_id} not found"))?;
+        let ctx = message_context(message);
+
+        // Executed in its own task, so the command is completed even if the
+        // request is dropped
+        let runtime = self.clone();
+        let name = name.clone();
+        let id = id.to_string();
+        let command = command.to_string();
+        let data = data.clone();
+        let outcome = tokio::spawn(
+            async move { runtime.execute_direct(name, id, ctx, command, data).await }
+                .in_current_span(),
+        )
+        .await?;
+
+        match outcome {
+            Some(outcome) => Ok(Some(outcome)),
+            None => waiter.outcome().await.map(Some),
+        }
+    }
+
+    /// Executes a submitted command directly, returning its outcome, or `None`
+    /// if the command subscription handles it instead.
+    async fn execute_direct(
+        &self,
+        name: ModuleName,
+        id: String,
+        ctx: Context,
+        command: String,
+        payload: Value,
+    ) -> Option<Outcome> {
+        let command_id = ctx.id;
+        self.direct_commands.lock().unwrap().insert(command_id);
+        let result = self
+            .execute(name, id, ctx.clone(), command.clone(), payload.clone())
+            .await;
+        let outcome = match result {
+            Err(err) if err.is::<CommandAlreadyHandled>() || err.is::<CommandNotNext>() => None,
+            result => Some(self.complete_command(ctx, command, payload, result).await),
+        };
+        self.direct_commands.lock().unwrap().remove(&command_id);
+
+        outcome
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn write_command(
+        &self,
+        stream_name: &StreamName,
+        command_id: Uuid,
+        command: &str,
+        data: &Value,
+        module_version: Option<&VersionReq>,
+        correlation_id: Option<&str>,
+    ) -> Result<i64> {
+        // Commands start a new correlation unless continuing an existing one
+        let correlation_id = correlation_id
+            .map(str::to_string)
+            .unwrap_or_else(|| command_id.to_string());
+        let mut property_values = vec![(CORRELATION_ID_PROPERTY, Value::String(correlation_id))];
+        if let Some(version_req) = module_version {
+            property_values.push((
+                MODULE_VERSION_PROPERTY,
+                Value::String(version_req.to_string()),
+            ));
+        }
+        if let Some(trace_context) = telemetry::current_trace_context() {
+            property_values.push((
+                TRACEPARENT_PROPERTY,
+                Value::String(trace_context.traceparent),
+            ));
+            if let Some(tracestate) = trace_context.tracestate {
+                property_values.push((TRACESTATE_PROPERTY, Value::String(tracestate)));
+            }
+        }
+        let opts = WriteMessageOpts::builder()
+            .id(command_id)
+            .metadata(MetadataRef {
+                properties: property_values
+                    .iter()
+                    .map(|(name, value)| (*name, value))
+                    .collect(),
+                ..Default::default()
+            })
+            .build();
         let position = MessageStore::write_message(
             &self.message_store,
             &stream_name.to_string(),
             command,
             data,
-            &WriteMessageOpts::default(),
+            &opts,
         )
-        .await?;
+        .await
+        .map_err(|err| {
+            // Message ids are unique in the message store
+            if is_unique_violation(&err) {
+                DuplicateCommandError(command_id).into()
+            } else {
+                anyhow::Error::from(err)
+            }
+        })?;
 
         Ok(position)
     }
 
+    /// Records the outcome of an executed command, dead lettering it if it
+    /// failed, and notifies anyone waiting for it.
+    async fn complete_command(
+        &self,
+        ctx: Context,
+        command: String,
+        payload: Value,
+        result: Result<ExecuteResult>,
+    ) -> Outcome {
+        if matches!(&result, Err(err) if err.is::<CommandAlreadyHandled>()) {
+            return self.complete_handled_command(ctx).await;
+        }
+
+        let outcome = Outcome::new(&result);
+        if let Err(err) = self.outcomes.record(&ctx, outcome.clone()).await {
+            error!(command_id = %ctx.id, "{err}");
+        }
+        self.outcome_waiters.notify(ctx.id, &outcome);
+        if let Err(err) = result {
+            self.push_dead_letter(ctx, command, payload, &err).await;
+        }
+
+        outcome
+    }
+
+    /// Returns the outcome recorded when a redelivered command was first
+    /// handled, recording it as ignored if the runtime stopped before
+    /// recording it.
+    async fn complete_handled_command(&self, ctx: Context) -> Outcome {
+        if self.direct_commands.lock().unwrap().contains(&ctx.id) {
+            // Recorded once its direct execution completes
+            return Outcome::Ignored {
+                reason: Some(CommandAlreadyHandled(ctx.id).to_string()),
+            };
+        }
+        let outcome = match self.outcomes.find(&ctx.stream_name, ctx.id).await {
+            Ok(Some(outcome)) => outcome,
+            Ok(None) => {
+                let outcome = Outcome::Ignored {
+                    reason: Some(CommandAlreadyHandled(ctx.id).to_string()),
+                };
+                if let Err(err) = self.outcomes.record(&ctx, outcome.clone()).await {
+                    error!(command_id = %ctx.id, "{err}");
+                }
+                outcome
+            }
+            Err(err) => {
+                error!(command_id = %ctx.id, "failed to find command outcome: {err}");
+                Outcome::new(&Err(CommandAlreadyHandled(ctx.id).into()))
+            }
+        };
+        self.outcome_waiters.notify(ctx.id, &outcome);
+
+        outcome
+    }
+
+    /// Lists the unresolved dead letters of a module.
+    pub async fn list_dead_letters(&self, name: &ModuleName) -> Result<Vec<DeadLetterEntry>> {
+        self.dead_letters.list(name).await
+    }
+
+    /// Returns an unresolved dead letter of a module.
+    pub async fn get_dead_letter(&self, name: &ModuleName, id: Uuid) -> Result<DeadLetterEntry> {
+        self.dead_letters.get(name, id).await
+    }
+
+    /// Resubmits a dead lettered command with a new command ID, returning the
+    /// new ID.
+    pub async fn retry_dead_letter(&self, name: &ModuleName, id: Uuid) -> Result<Uuid> {
+        let entry = self.dead_letters.get(name, id).await?;
+        let DeadLetter {
+            ctx,
+            command,
+            payload,
+            ..
+        } = &entry.dead_letter;
+        let entity_id = ctx
+            .stream_name
+            .id
+            .as_ref()
+            .ok_or_else(|| anyhow!("missing id from command"))?
+            .cardinal_id()
+            .to_string();
+        let module_version: Option<VersionReq> = ctx
+            .metadata
+            .properties
+            .get(MODULE_VERSION_PROPERTY)
+            .and_then(|version_req| version_req.as_str())
+            .map(str::parse)
+            .transpose()?;
+
+        let command_id = Uuid::new_v4();
+        self.submit_command(
+            name,
+            &entity_id,
+            Some(command_id),
+            command,
+            payload,
+            module_version.as_ref(),
+            ctx.correlation_id(),
+        )
+        .await?;
+        self.dead_letters.retried(&entry, command_id).await?;
+
+        Ok(command_id)
+    }
+
+    /// Discards a dead lettered command.
+    pub async fn discard_dead_letter(&self, name: &ModuleName, id: Uuid) -> Result<()> {
+        let entry = self.dead_letters.get(name, id).await?;
+        self.dead_letters.discard(&entry).await
+    }
+
+    /// Writes a failed command to its entity's dead letter stream.
+    async fn push_dead_letter(
+        &self,
+        ctx: Context,
+        command: String,
+        payload: Value,
+        err: &anyhow::Error,
+    ) {
+        let module_version = err
+            .downcast_ref::<CommandFailed>()
+            .map(|failed| failed.module_id.version.clone());
+        let dead_letter = DeadLetter::new(ctx, command, payload, err, module_version);
+        if let Err(err) = self.dead_letters.push(&dead_letter).await {
+            error!(
+                command_id = %dead_letter.ctx.id,
+                "failed to dead letter command: {err}"
+            );
+        }
+    }
+
+    /// Returns the version requirement pinned for a module, defaulting to any
+    /// version.
+    pub async fn version_req(&self, name: &ModuleName) -> VersionReq {
+        self.version_reqs
+            .read()
+            .await
+            .get(name)
+            .cloned()
+            .unwrap_or(VersionReq::STAR)
+    }
+
+    /// Pins the version requirement serving a module, rolling running
+    /// handlers or event subscriptions onto the latest matching version.
+    pub async fn pin_module_version(
+        &self,
+        name: ModuleName,
+        version_req: VersionReq,
+    ) -> Result<()> {
+        if self
+            .registry
+            .read()
+            .await
+            .get_module(&name, &version_req)
+            .is_none()
+        {
+            bail!("no published version of module '{name}' matches '{version_req}'");
+        }
+
+        self.version_reqs
+            .write()
+            .await
+            .insert(name.clone(), version_req);
+        let kind = self.module_kinds.read().await.get(&name).copied();
+        if let Some(kind @ (ModuleKind::ProcessManager | ModuleKind::Projection)) = kind {
+            self.restart_event_subscription(name, kind).await;
+            return Ok(());
+        }
+        self.command_router.upgrade(name).await
+    }
+
     pub async fn publish_module(&self, schema_module: SchemaModule) -> Result<()> {
-        let mut registry = self.registry.write().await;
+        // Compiling before saving also rejects invalid modules. The registry is
+        // only locked once compiled, so loading modules isn't held up by it.
+        self.compile_cache
+            .precompile(schema_module.module())
+            .await?;
+
+        // Every version of a module must implement the same world
+        let module_name: ModuleName = schema_module.schema().aggregate.name.parse()?;
+        let module_id = ModuleID::new(module_name.clone(), schema_module.schema().version.clone());
+        let kind = self
+            .detect_module_kind(&module_id, schema_module.module())
+            .await?;
 
+        // The version is checked and the module added under the same lock, so
+        // concurrent publishes of the same version can't both succeed
+        let mut registry = self.registry.write().await;
         let latest_version = self
             .registry_store
             .load_module_latest_version(&schema_module.schema().aggregate.name)
@@ -204,64 +1743,402 @@ impl Runtime {
                 bail!("version must be greater than latest version {latest_version}");
             }
         }
+        if let Some(existing_kind) = self.module_kinds.read().await.get(&module_name) {
+            if *existing_kind != kind {
+                bail!("module '{module_name}' is a {existing_kind}, but the published version is a {kind}");
+            }
+        }
 
         self.registry_store
             .save_schema_module(schema_module.schema(), schema_module.module())
             .await?;
 
-        let (schema, module) = schema_module.into_inner();
-        let module_name: ModuleName = schema.aggregate.name.parse()?;
-        let id = ModuleID::new(module_name.clone(), schema.version);
-        registry.add_module(id, module);
+        let (_, module) = schema_module.into_inner();
+        registry.add_module(module_id, module);
+        self.module_kinds
+            .write()
+            .await
+            .insert(module_name.clone(), kind);
+        drop(registry);
 
-        self.start_module(module_name);
+        match kind {
+            ModuleKind::Aggregate => {
+                self.start_module(module_name.clone());
+                self.command_router.upgrade(module_name).await?;
+            }
+            ModuleKind::ProcessManager | ModuleKind::Projection => {
+                self.restart_event_subscription(module_name, kind).await
+            }
+        }
 
         Ok(())
     }
 
+    /// Detects the world implemented by a module binary.
+    async fn detect_module_kind(&self, id: &ModuleID, binary: &[u8]) -> Result<ModuleKind> {
+        let limits = self.config.module_limits(&id.name);
+        check_instance_count(binary, id, limits)?;
+        let component = self.compile_cache.load(binary).await?;
+        ModuleKind::detect(&self.engine, id, limits, &component).await
+    }
+
+    /// Loads the latest module version matching the version requirement,
+    /// compiling it if it's not already loaded.
     pub async fn load_module(
         &self,
         name: &ModuleName,
         version: &VersionReq,
     ) -> Result<(ModuleID, Arc<Module>)> {
-        if let Some((module_id, module)) = self.load_module_opt(name, version).await {
-            return Ok((module_id, module));
-        }
-
-        let (module, module_id) = {
-            let registry = self.registry.read().await;
-            let (version, binary) = registry
-                .get_module(name, version)
-                .ok_or_else(|| anyhow!("module does not exist"))?;
-            let module = Module::from_binary(
-                self.engine.clone(),
-                ModuleID::new(name.clone(), version.clone()),
-                binary,
-            )
-            .await?;
-            let module_id = ModuleID::new(name.clone(), version.clone());
-            (module, module_id)
-        };
-        let mut modules = self.modules.write().await;
-        let module = modules
-            .entry(module_id.clone())
-            .or_insert_with(|| Arc::new(module));
+        self.load(
+            name,
+            version,
+            &self.modules,
+            |engine, id, limits, component| async move {
+                Module::from_component(engine, id, limits, &component).await
+            },
+        )
+        .await
+    }
 
-        Ok((module_id, Arc::clone(module)))
+    /// Loads the latest process manager version matching the version
+    /// requirement, compiling it if it's not already loaded.
+    pub async fn load_process_manager(
+        &self,
+        name: &ModuleName,
+        version: &VersionReq,
+    ) -> Result<(ModuleID, Arc<ProcessManagerModule>)> {
+        self.load(
+            name,
+            version,
+            &self.process_managers,
+            |engine, id, limits, component| async move {
+                ProcessManagerModule::from_component(engine, id, limits, &component).await
+            },
+        )
+        .await
     }
 
-    async fn load_module_opt(
+    /// Loads the latest projection version matching the version
+    /// requirement, compiling it if it's not already loaded.
+    pub async fn load_projection(
         &self,
         name: &ModuleName,
         version: &VersionReq,
-    ) -> Option<(ModuleID, Arc<Module>)> {
-        let modules_read = self.modules.read().await;
-        modules_read.iter().find_map(|(module_id, module)| {
-            if &module_id.name == name && version.matches(&module_id.version) {
-                Some((module_id.clone(), Arc::clone(module)))
-            } else {
-                None
+    ) -> Result<(ModuleID, Arc<ProjectionModule>)> {
+        self.load(
+            name,
+            version,
+            &self.projections,
+            |engine, id, limits, component| async move {
+                ProjectionModule::from_component(engine, id, limits, &component).await
+            },
+        )
+        .await
+    }
+
+    /// Loads the latest version of a module matching the version requirement
+    /// into `loaded`, compiling it with the compile cache and creating it with
+    /// `from_component` if it's not already loaded.
+    async fn load<M, F, Fut>(
+        &self,
+        name: &ModuleName,
+        version: &VersionReq,
+        loaded: &RwLock<BTreeMap<ModuleID, Arc<M>>>,
+        from_component: F,
+    ) -> Result<(ModuleID, Arc<M>)>
+    where
+        F: FnOnce(Engine, ModuleID, ModuleLimits, Component) -> Fut,
+        Fut: Future<Output = Result<M>>,
+    {
+        let registry = self.registry.read().await;
+        let (version, binary) = registry
+            .get_module(name, version)
+            .ok_or_else(|| anyhow!("module does not exist"))?;
+        let module_id = ModuleID::new(name.clone(), version.clone());
+
+        if let Some(reason) = self.unhealthy_modules.read().await.get(&module_id) {
+            return Err(UnhealthyModuleError {
+                name: module_id.name,
+                version: module_id.version,
+                reason: reason.clone(),
             }
-        })
+            .into());
+        }
+
+        if let Some(module) = loaded.read().await.get(&module_id) {
+            return Ok((module_id, Arc::clone(module)));
+        }
+
+        let limits = self.config.module_limits(name).clone();
+        check_instance_count(binary, &module_id, &limits)?;
+        let start = Instant::now();
+        let component = self.compile_cache.load(binary).await?;
+        histogram!(
+            MODULE_COMPILE_DURATION,
+            start.elapsed().as_secs_f64(),
+            "module" => name.to_string()
+        );
+        drop(registry);
+
+        let module =
+            from_component(self.engine.clone(), module_id.clone(), limits, component).await?;
+
+        let mut loaded = loaded.write().await;
+        let module = loaded
+            .entry(module_id.clone())
+            .or_insert_with(|| Arc::new(module));
+
+        Ok((module_id, Arc::clone(module)))
+    }
+
+    /// Records a guest trapping while executing a module.
+    ///
+    /// Modules which exceeded their resource limits are marked unhealthy, and
+    /// refuse to load until the runtime is restarted.
+    pub async fn record_trap(&self, module_id: &ModuleID, err: &anyhow::Error) {
+        warn!(
+            module_name = %module_id.name,
+            version = %module_id.version,
+            "module trapped: {err}"
+        );
+
+        if let Some(err) = err.downcast_ref::<ResourceLimitExceeded>() {
+            error!(
+                module_name = %module_id.name,
+                version = %module_id.version,
+                "marking module as unhealthy: {err}"
+            );
+            self.unhealthy_modules
+                .write()
+                .await
+                .insert(module_id.clone(), err.to_string());
+        }
+    }
+
+    /// Returns the module versions marked unhealthy, with the reason.
+    pub async fn unhealthy_modules(&self) -> HashMap<ModuleID, String> {
+        self.unhealthy_modules.read().await.clone()
+    }
+}
+
+fn event_stream_name(name: &ModuleName, id: &str) -> Result<StreamName> {
+    Ok(StreamName {
+        category: Category::normalize(name).parse()?,
+        id: Some(id.parse()?),
+    })
+}
+
+/// Clamps a requested batch size to the maximum page size.
+fn read_batch_size(batch_size: Option<i64>) -> i64 {
+    batch_size
+        .unwrap_or(DEFAULT_READ_BATCH_SIZE)
+        .clamp(1, MAX_READ_BATCH_SIZE)
+}
+
+fn event_page(
+    messages: Vec<GenericMessage>,
+    next_position: i64,
+    batch_size: i64,
+    event_types: &[String],
+) -> EventPage {
+    let caught_up = (messages.len() as i64) < batch_size;
+    let messages = messages
+        .into_iter()
+        .filter(|message| event_types.is_empty() || event_types.contains(&message.msg_type))
+        .collect();
+
+    EventPage {
+        messages,
+        next_position,
+        caught_up,
+    }
+}
+
+pub(crate) fn command_stream_name(name: &ModuleName, id: &str) -> Result<StreamName> {
+    let category = Category::new(Category::normalize(name), vec!["command".to_string()])?;
+    Ok(StreamName {
+        category,
+        id: Some(id.parse()?),
+    })
+}
+
+fn is_unique_violation(err: &message_db::Error) -> bool {
+    match err {
+        message_db::Error::Database(err) => err
+            .as_database_error()
+            .and_then(|err| err.code())
+            .map_or(false, |code| code == UNIQUE_VIOLATION_CODE),
+        _ => false,
+    }
+}
+
+fn message_context(message: GenericMessage) -> Context {
+    Context::new(
+        message.id,
+        message.stream_name,
+        message.position,
+        message.global_position,
+        message.metadata,
+        message.time,
+    )
+}
+
+/// Tracks the position of a command subscription handling commands
+/// concurrently, only advancing past commands once they have completed.
+struct CommandPositions {
+    /// Global positions of the commands being handled, with the time they
+    /// were written.
+    in_flight: BTreeMap<i64, DateTime<Utc>>,
+    /// Global position of the last command read.
+    last_read: i64,
+    /// Last position recorded.
+    recorded: i64,
+    /// Number of commands completed since the position was last recorded.
+    completed_since_recorded: u64,
+}
+
+impl CommandPositions {
+    /// Starts tracking after the last recorded position.
+    fn new(recorded: i64) -> Self {
+        CommandPositions {
+            in_flight: BTreeMap::new(),
+            last_read: recorded,
+            recorded,
+            completed_since_recorded: 0,
+        }
+    }
+
+    /// Marks a command written at `time` as being handled.
+    fn start(&mut self, position: i64, time: DateTime<Utc>) {
+        self.in_flight.insert(position, time);
+        self.last_read = position;
+    }
+
+    /// Marks a command as skipped, which never holds the position back.
+    fn skip(&mut self, position: i64) {
+        self.last_read = position;
+    }
+
+    /// Marks a command as completed, returning the position to record once
+    /// [`SUBSCRIPTION_POSITION_UPDATE_INTERVAL`] commands have completed since
+    /// it was last recorded.
+    fn complete(&mut self, position: i64) -> Option<i64> {
+        self.in_flight.remove(&position);
+        self.completed_since_recorded += 1;
+        if self.completed_since_recorded < SUBSCRIPTION_POSITION_UPDATE_INTERVAL {
+            return None;
+        }
+        let position = self.unrecorded()?;
+        self.recorded = position;
+        self.completed_since_recorded = 0;
+
+        Some(position)
+    }
+
+    /// Returns the position before the first command still being handled, or
+    /// the last command read if none are, unless already recorded.
+    fn unrecorded(&self) -> Option<i64> {
+        let position = match self.in_flight.keys().next() {
+            Some(first) => first - 1,
+            None => self.last_read,
+        };
+
+        (position > self.recorded).then_some(position)
+    }
+
+    /// Records the subscription lag as the age of the oldest command still
+    /// being handled, or zero once all read commands were handled.
+    fn record_lag(&self, category_name: &str) {
+        let lag = match self.in_flight.values().next() {
+            Some(time) => (Utc::now() - *time)
+                .to_std()
+                .unwrap_or_default()
+                .as_secs_f64(),
+            None => 0.0,
+        };
+        gauge!(SUBSCRIPTION_LAG, lag, "category" => category_name.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(position: i64, msg_type: &str) -> GenericMessage {
+        GenericMessage {
+            id: Uuid::new_v4(),
+            stream_name: "counter-1".parse().unwrap(),
+            msg_type: msg_type.to_string(),
+            position,
+            global_position: position,
+            data: Default::default(),
+            metadata: Default::default(),
+            time: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn read_batch_size_is_clamped() {
+        assert_eq!(read_batch_size(None), DEFAULT_READ_BATCH_SIZE);
+        assert_eq!(read_batch_size(Some(10)), 10);
+        assert_eq!(read_batch_size(Some(0)), 1);
+        assert_eq!(read_batch_size(Some(-5)), 1);
+        assert_eq!(
+            read_batch_size(Some(MAX_READ_BATCH_SIZE + 1)),
+            MAX_READ_BATCH_SIZE
+        );
+    }
+
+    #[test]
+    fn event_page_is_caught_up_when_not_full() {
+        let full = vec![message(0, "A"), message(1, "A")];
+        let page = event_page(full, 2, 2, &[]);
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.next_position, 2);
+        assert!(!page.caught_up);
+
+        let partial = vec![message(2, "A")];
+        assert!(event_page(partial, 3, 2, &[]).caught_up);
+        assert!(event_page(Vec::new(), 3, 2, &[]).caught_up);
+    }
+
+    #[test]
+    fn event_page_filters_without_catching_up() {
+        let messages = vec![message(0, "A"), message(1, "B"), message(2, "A")];
+        let page = event_page(messages, 3, 3, &["B".to_string()]);
+
+        let types: Vec<_> = page.messages.iter().map(|m| m.msg_type.as_str()).collect();
+        assert_eq!(types, ["B"]);
+        assert_eq!(page.next_position, 3);
+        // Filtered events still count towards the page being full
+        assert!(!page.caught_up);
+    }
+
+    #[test]
+    fn command_positions_wait_for_earlier_commands() {
+        let mut positions = CommandPositions::new(10);
+        positions.start(11, Utc::now());
+        positions.start(14, Utc::now());
+        positions.skip(15);
+
+        positions.complete(14);
+        assert_eq!(positions.unrecorded(), None);
+        positions.complete(11);
+        assert_eq!(positions.unrecorded(), Some(15));
+    }
+
+    #[test]
+    fn command_positions_record_every_interval() {
+        let mut positions = CommandPositions::new(0);
+        let interval = SUBSCRIPTION_POSITION_UPDATE_INTERVAL as i64;
+        for position in 1..=interval {
+            positions.start(position, Utc::now());
+        }
+
+        for position in 2..=interval {
+            assert_eq!(positions.complete(position), None);
+        }
+        assert_eq!(positions.complete(1), Some(interval));
+        assert_eq!(positions.unrecorded(), None);
     }
 }
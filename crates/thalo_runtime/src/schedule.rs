@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context as AnyhowContext, Result};
+use chrono::{DateTime, Utc};
+use message_db::database::{GetCategoryMessagesOpts, MessageStore, WriteMessageOpts};
+use message_db::message::MessageData;
+use message_db::stream_name::{Category, StreamName};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{trace, warn};
+use uuid::Uuid;
+
+use crate::module::ModuleName;
+
+const SCHEDULED_MSG_TYPE: &str = "Scheduled";
+const SUBMITTED_MSG_TYPE: &str = "Submitted";
+const CANCELLED_MSG_TYPE: &str = "Cancelled";
+/// Category of the schedule streams.
+const SCHEDULE_CATEGORY: &str = "thalo:schedule";
+/// Separator of the parts of a schedule stream ID.
+const ID_SEPARATOR: char = '+';
+/// Number of messages loaded per query when reading schedules.
+const READ_BATCH_SIZE: i64 = 1_000;
+
+/// Stores scheduled commands in `thalo:schedule-<aggregate>+<entity_id>+<id>`
+/// streams, scoping schedule IDs to the aggregate instance which scheduled
+/// them. Entity and schedule IDs can't contain `+`, so each stream has a
+/// single owner.
+///
+/// A schedule is pending while the last message of its stream is
+/// `Scheduled`. Schedules are resolved by appending a `Submitted` or
+/// `Cancelled` message to the same stream, and replaced by appending another
+/// `Scheduled` message.
+#[derive(Clone)]
+pub struct ScheduleStore {
+    message_store: MessageStore,
+}
+
+/// The aggregate instance which scheduled a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScheduleOwner<'a> {
+    /// Entity name of the aggregate.
+    pub entity_name: &'a str,
+    pub entity_id: &'a str,
+}
+
+/// A command to be submitted once it comes due.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub aggregate: ModuleName,
+    pub entity_id: String,
+    pub command: String,
+    pub payload: Value,
+    pub due: DateTime<Utc>,
+    /// Correlation ID of the command which scheduled the command.
+    pub correlation_id: Option<String>,
+}
+
+/// A schedule which has not been submitted or cancelled yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingSchedule {
+    /// ID of the schedule's stream, the schedule ID scoped to the aggregate
+    /// instance which scheduled it.
+    pub id: String,
+    /// Message ID of the schedule, used as the ID of the submitted command.
+    pub message_id: Uuid,
+    /// Version of the schedule stream.
+    pub version: i64,
+    pub schedule: Schedule,
+}
+
+/// Pending schedules, kept up to date by reading the schedule category.
+pub struct PendingSchedules {
+    store: ScheduleStore,
+    position: i64,
+    queue: ScheduleQueue,
+}
+
+/// Pending schedules by stream ID, replaced when rescheduled.
+#[derive(Default)]
+struct ScheduleQueue {
+    pending: HashMap<String, PendingSchedule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Resolution {
+    /// ID of the submitted command, if submitted.
+    #[serde(default)]
+    command_id: Option<Uuid>,
+}
+
+impl ScheduleStore {
+    pub fn new(message_store: MessageStore) -> Self {
+        ScheduleStore { message_store }
+    }
+
+    /// Schedules a command for an aggregate instance, replacing any of its
+    /// pending schedules with the same ID.
+    pub async fn schedule(
+        &self,
+        owner: &ScheduleOwner<'_>,
+        id: &str,
+        schedule: &Schedule,
+    ) -> Result<()> {
+        let stream_name = schedule_stream_name(&owner.stream_id(id)?)?.to_string();
+        MessageStore::write_message(
+            &self.message_store,
+            &stream_name,
+            SCHEDULED_MSG_TYPE,
+            schedule,
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to write schedule")?;
+
+        trace!(stream_name = %stream_name, due = %schedule.due, "scheduled command");
+
+        Ok(())
+    }
+
+    /// Cancels a schedule of an aggregate instance, returning whether it was
+    /// pending.
+    pub async fn cancel(&self, owner: &ScheduleOwner<'_>, id: &str) -> Result<bool> {
+        let stream_name = schedule_stream_name(&owner.stream_id(id)?)?.to_string();
+        let message = MessageStore::get_last_stream_message::<MessageData, _>(
+            &self.message_store,
+            &stream_name,
+            None,
+        )
+        .await
+        .context("failed to load schedule")?;
+        let version = match message {
+            Some(message) if message.msg_type == SCHEDULED_MSG_TYPE => message.position,
+            _ => return Ok(false),
+        };
+
+        self.resolve(&stream_name, CANCELLED_MSG_TYPE, None, version)
+            .await?;
+        trace!(stream_name = %stream_name, "cancelled schedule");
+
+        Ok(true)
+    }
+
+    /// Marks a pending schedule as submitted, failing if it was replaced or
+    /// cancelled in the meantime.
+    pub async fn submitted(&self, pending: &PendingSchedule, command_id: Uuid) -> Result<()> {
+        let stream_name = schedule_stream_name(&pending.id)?.to_string();
+        self.resolve(
+            &stream_name,
+            SUBMITTED_MSG_TYPE,
+            Some(command_id),
+            pending.version,
+        )
+        .await
+    }
+
+    async fn resolve(
+        &self,
+        stream_name: &str,
+        msg_type: &str,
+        command_id: Option<Uuid>,
+        expected_version: i64,
+    ) -> Result<()> {
+        MessageStore::write_message(
+            &self.message_store,
+            stream_name,
+            msg_type,
+            &Resolution { command_id },
+            &WriteMessageOpts::builder()
+                .id(Uuid::new_v4())
+                .expected_version(expected_version)
+                .build(),
+        )
+        .await
+        .context("failed to resolve schedule")?;
+
+        Ok(())
+    }
+}
+
+impl ScheduleOwner<'_> {
+    /// Returns the ID of the stream of one of the owner's schedules.
+    fn stream_id(&self, id: &str) -> Result<String> {
+        // Parts are joined with `+`, so they can't contain it themselves
+        if self.entity_id.contains(ID_SEPARATOR) {
+            bail!(
+                "entity id '{}' of a schedule can't contain '{ID_SEPARATOR}'",
+                self.entity_id
+            );
+        }
+        if id.contains(ID_SEPARATOR) {
+            bail!("schedule id '{id}' can't contain '{ID_SEPARATOR}'");
+        }
+
+        Ok(format!(
+            "{}{ID_SEPARATOR}{}{ID_SEPARATOR}{id}",
+            self.entity_name, self.entity_id
+        ))
+    }
+}
+
+impl PendingSchedules {
+    pub fn new(store: ScheduleStore) -> Self {
+        PendingSchedules {
+            store,
+            position: 1,
+            queue: ScheduleQueue::default(),
+        }
+    }
+
+    /// Reads the schedule messages written since the last refresh.
+    pub async fn refresh(&mut self) -> Result<()> {
+        loop {
+            let opts = GetCategoryMessagesOpts::builder()
+                .position(self.position)
+                .batch_size(READ_BATCH_SIZE)
+                .build();
+            let messages = MessageStore::get_category_messages::<MessageData, _>(
+                &self.store.message_store,
+                SCHEDULE_CATEGORY,
+                &opts,
+            )
+            .await?;
+            let last_position = match messages.last() {
+                Some(message) => message.global_position,
+                None => break,
+            };
+            let batch_len = messages.len();
+
+            for message in messages {
+                let id = match &message.stream_name.id {
+                    Some(id) => id.to_string(),
+                    None => {
+                        warn!(message_id = %message.id, "missing id from schedule stream");
+                        continue;
+                    }
+                };
+                if message.msg_type != SCHEDULED_MSG_TYPE {
+                    self.queue.remove(&id);
+                    continue;
+                }
+
+                match serde_json::from_value(message.data) {
+                    Ok(schedule) => {
+                        self.queue.insert(PendingSchedule {
+                            id,
+                            message_id: message.id,
+                            version: message.position,
+                            schedule,
+                        });
+                    }
+                    Err(err) => {
+                        warn!(schedule_id = %id, "failed to deserialize schedule: {err}");
+                        self.queue.remove(&id);
+                    }
+                }
+            }
+
+            self.position = last_position + 1;
+            if (batch_len as i64) < READ_BATCH_SIZE {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes and returns the schedules due by `now`, earliest first.
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<PendingSchedule> {
+        self.queue.take_due(now)
+    }
+
+    /// Adds a schedule back, such as after failing to submit it.
+    pub fn insert(&mut self, pending: PendingSchedule) {
+        self.queue.insert(pending);
+    }
+}
+
+impl ScheduleQueue {
+    /// Adds a schedule, replacing any pending schedule with the same ID.
+    fn insert(&mut self, pending: PendingSchedule) {
+        self.pending.insert(pending.id.clone(), pending);
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.pending.remove(id);
+    }
+
+    fn take_due(&mut self, now: DateTime<Utc>) -> Vec<PendingSchedule> {
+        let due_ids: Vec<_> = self
+            .pending
+            .values()
+            .filter(|pending| pending.schedule.due <= now)
+            .map(|pending| pending.id.clone())
+            .collect();
+        let mut due: Vec<_> = due_ids
+            .iter()
+            .filter_map(|id| self.pending.remove(id))
+            .collect();
+        due.sort_by_key(|pending| pending.schedule.due);
+        due
+    }
+}
+
+fn schedule_stream_name(stream_id: &str) -> Result<StreamName> {
+    let category = Category::new("thalo".to_string(), vec!["schedule".to_string()])?;
+    Ok(StreamName {
+        category,
+        id: Some(stream_id.parse()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn pending(id: &str, due: DateTime<Utc>) -> PendingSchedule {
+        PendingSchedule {
+            id: id.to_string(),
+            message_id: Uuid::new_v4(),
+            version: 0,
+            schedule: Schedule {
+                aggregate: "counter".parse().unwrap(),
+                entity_id: "1".to_string(),
+                command: "Increment".to_string(),
+                payload: Value::Null,
+                due,
+                correlation_id: None,
+            },
+        }
+    }
+
+    #[test]
+    fn take_due_returns_earliest_first() {
+        let now = Utc::now();
+        let mut queue = ScheduleQueue::default();
+        queue.insert(pending("a", now - Duration::seconds(1)));
+        queue.insert(pending("b", now + Duration::seconds(1)));
+        queue.insert(pending("c", now - Duration::seconds(5)));
+
+        let due: Vec<_> = queue.take_due(now).into_iter().map(|p| p.id).collect();
+        assert_eq!(due, ["c", "a"]);
+        assert!(queue.take_due(now).is_empty());
+
+        let due: Vec<_> = queue
+            .take_due(now + Duration::seconds(1))
+            .into_iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(due, ["b"]);
+    }
+
+    #[test]
+    fn take_due_uses_replaced_schedule() {
+        let now = Utc::now();
+        let mut queue = ScheduleQueue::default();
+        queue.insert(pending("a", now - Duration::seconds(1)));
+        let replacement = pending("a", now + Duration::seconds(1));
+        queue.insert(replacement.clone());
+
+        assert!(queue.take_due(now).is_empty());
+        assert_eq!(queue.take_due(now + Duration::seconds(1)), [replacement]);
+    }
+
+    #[test]
+    fn take_due_skips_removed_schedule() {
+        let now = Utc::now();
+        let mut queue = ScheduleQueue::default();
+        queue.insert(pending("a", now - Duration::seconds(1)));
+        queue.remove("a");
+
+        assert!(queue.take_due(now).is_empty());
+    }
+
+    #[test]
+    fn schedule_ids_are_scoped_to_their_owner() {
+        let owner = ScheduleOwner {
+            entity_name: "counter",
+            entity_id: "1",
+        };
+        let other = ScheduleOwner {
+            entity_name: "counter",
+            entity_id: "2",
+        };
+
+        let stream_name = schedule_stream_name(&owner.stream_id("reminder").unwrap()).unwrap();
+        assert_eq!(stream_name.to_string(), "thalo:schedule-counter+1+reminder");
+        assert_ne!(
+            owner.stream_id("reminder").unwrap(),
+            other.stream_id("reminder").unwrap()
+        );
+
+        // Would both be `counter+1+a+b` if the parts could contain the separator
+        let separated_entity = ScheduleOwner {
+            entity_name: "counter",
+            entity_id: "1+a",
+        };
+        assert!(separated_entity.stream_id("b").is_err());
+        assert!(owner.stream_id("a+b").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context as AnyhowContext, Result};
+use message_db::database::{MessageStore, WriteMessageOpts};
+use message_db::stream_name::{Category, StreamName};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::trace;
+use uuid::Uuid;
+
+const SNAPSHOT_MSG_TYPE: &str = "Snapshot";
+
+/// Stores serialized aggregate state in `<entity>:snapshot-<id>` streams.
+///
+/// Snapshots are tagged with the module version which produced the state, and
+/// the stream position of the last event applied to it.
+#[derive(Clone)]
+pub struct SnapshotStore {
+    message_store: MessageStore,
+    interval: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub module_version: Version,
+    pub position: i64,
+    /// Position in the entity's command stream of the last command handled
+    /// when the snapshot was taken.
+    #[serde(default)]
+    pub command_position: Option<i64>,
+    pub state: Value,
+}
+
+impl SnapshotStore {
+    /// Creates a new snapshot store, taking a snapshot every `interval`
+    /// events. An interval of `0` disables snapshots.
+    pub fn new(message_store: MessageStore, interval: u64) -> Self {
+        SnapshotStore {
+            message_store,
+            interval,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.interval > 0
+    }
+
+    /// Returns whether a snapshot should be taken, given the position of the
+    /// latest snapshot and the current stream version.
+    pub fn should_snapshot(&self, snapshot_position: i64, version: i64) -> bool {
+        interval_reached(self.interval, snapshot_position, version)
+    }
+
+    /// Loads the latest snapshot for a stream, if it was taken by the given
+    /// module version.
+    pub async fn load(
+        &self,
+        stream_name: &StreamName,
+        module_version: &Version,
+    ) -> Result<Option<Snapshot>> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+
+        let snapshot_stream_name = snapshot_stream_name(stream_name)?.to_string();
+        let message = MessageStore::get_last_stream_message::<Snapshot, _>(
+            &self.message_store,
+            &snapshot_stream_name,
+            None,
+        )
+        .await
+        .context("failed to load snapshot")?;
+
+        match message {
+            Some(message) if &message.data.module_version == module_version => {
+                trace!(
+                    stream_name = %stream_name,
+                    position = message.data.position,
+                    "loaded snapshot"
+                );
+                Ok(Some(message.data))
+            }
+            Some(message) => {
+                trace!(
+                    stream_name = %stream_name,
+                    snapshot_version = %message.data.module_version,
+                    module_version = %module_version,
+                    "ignoring snapshot from incompatible module version"
+                );
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save(&self, stream_name: &StreamName, snapshot: &Snapshot) -> Result<()> {
+        let snapshot_stream_name = snapshot_stream_name(stream_name)?.to_string();
+        MessageStore::write_message(
+            &self.message_store,
+            &snapshot_stream_name,
+            SNAPSHOT_MSG_TYPE,
+            snapshot,
+            &WriteMessageOpts::builder().id(Uuid::new_v4()).build(),
+        )
+        .await
+        .context("failed to save snapshot")?;
+
+        trace!(
+            stream_name = %stream_name,
+            position = snapshot.position,
+            "saved snapshot"
+        );
+
+        Ok(())
+    }
+}
+
+fn snapshot_stream_name(stream_name: &StreamName) -> Result<StreamName> {
+    let mut types = stream_name.category.types.clone();
+    types.push("snapshot".to_string());
+    Ok(StreamName {
+        category: Category::new(stream_name.category.entity_name.clone(), types)?,
+        id: stream_name.id.clone(),
+    })
+}
+
+/// Returns whether `interval` events were applied since the snapshot at
+/// `snapshot_position`, or -1 if none was taken.
+fn interval_reached(interval: u64, snapshot_position: i64, version: i64) -> bool {
+    interval > 0 && version - snapshot_position >= interval as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_reached_counts_events_since_snapshot() {
+        // Ten events applied at positions 0 to 9, with no snapshot taken
+        assert!(!interval_reached(10, -1, 8));
+        assert!(interval_reached(10, -1, 9));
+
+        assert!(!interval_reached(10, 9, 18));
+        assert!(interval_reached(10, 9, 19));
+        assert!(interval_reached(10, 9, 25));
+    }
+
+    #[test]
+    fn interval_reached_never_when_disabled() {
+        assert!(!interval_reached(0, -1, 0));
+        assert!(!interval_reached(0, -1, 1_000));
+    }
+}
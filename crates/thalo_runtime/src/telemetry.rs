@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use metrics::{describe_counter, describe_gauge, describe_histogram, histogram, Unit};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use thalo::{TraceContext, TRACEPARENT_PROPERTY, TRACESTATE_PROPERTY};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::module::ModuleID;
+
+/// Number of handled commands, labelled by aggregate, command and outcome.
+pub const COMMANDS_TOTAL: &str = "thalo_commands_total";
+/// Time taken to handle a command, labelled by aggregate and command.
+pub const COMMAND_DURATION: &str = "thalo_command_duration_seconds";
+/// Number of entity command handlers alive.
+pub const COMMAND_HANDLERS: &str = "thalo_command_handlers";
+/// Time taken to compile a module, labelled by module.
+pub const MODULE_COMPILE_DURATION: &str = "thalo_module_compile_duration_seconds";
+/// Time taken to instantiate a module, labelled by module.
+pub const MODULE_INSTANTIATE_DURATION: &str = "thalo_module_instantiate_duration_seconds";
+/// Time taken by calls into a module, labelled by module and function.
+pub const WASM_CALL_DURATION: &str = "thalo_wasm_call_duration_seconds";
+/// Lag of a command subscription, labelled by category.
+///
+/// Measured as the age of the oldest command the subscription read but has
+/// not finished handling, so it grows while commands wait behind slow ones and
+/// drops to zero once every read command was handled. Commands not read yet
+/// aren't counted, as the subscription reads ahead of handling them by up to
+/// its in-flight limit.
+pub const SUBSCRIPTION_LAG: &str = "thalo_subscription_lag_seconds";
+
+/// Registers the descriptions of the runtime's metrics with the installed
+/// recorder.
+pub fn describe_metrics() {
+    describe_counter!(COMMANDS_TOTAL, Unit::Count, "Number of handled commands");
+    describe_histogram!(
+        COMMAND_DURATION,
+        Unit::Seconds,
+        "Time taken to handle a command"
+    );
+    describe_gauge!(
+        COMMAND_HANDLERS,
+        Unit::Count,
+        "Number of entity command handlers alive"
+    );
+    describe_histogram!(
+        MODULE_COMPILE_DURATION,
+        Unit::Seconds,
+        "Time taken to compile or load a cached module"
+    );
+    describe_histogram!(
+        MODULE_INSTANTIATE_DURATION,
+        Unit::Seconds,
+        "Time taken to instantiate a module"
+    );
+    describe_histogram!(
+        WASM_CALL_DURATION,
+        Unit::Seconds,
+        "Time taken by calls into a module"
+    );
+    describe_gauge!(
+        SUBSCRIPTION_LAG,
+        Unit::Seconds,
+        "Age of the oldest command a command subscription has not finished handling"
+    );
+}
+
+/// Records the duration of a call into a module, which started at `start`.
+pub fn record_wasm_call(module_id: &ModuleID, function: &'static str, start: Instant) {
+    histogram!(
+        WASM_CALL_DURATION,
+        start.elapsed().as_secs_f64(),
+        "module" => module_id.name.to_string(),
+        "function" => function
+    );
+}
+
+/// Returns the W3C trace context of the current span, if it's being traced.
+pub fn current_trace_context() -> Option<TraceContext> {
+    let mut carrier: HashMap<String, String> = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut carrier);
+
+    Some(TraceContext {
+        traceparent: carrier.remove(TRACEPARENT_PROPERTY)?,
+        tracestate: carrier
+            .remove(TRACESTATE_PROPERTY)
+            .filter(|tracestate| !tracestate.is_empty()),
+    })
+}
+
+/// Continues a propagated trace, setting the parent of a span.
+pub fn set_parent(span: &Span, trace_context: &TraceContext) {
+    let mut carrier = HashMap::from([(
+        TRACEPARENT_PROPERTY.to_string(),
+        trace_context.traceparent.clone(),
+    )]);
+    if let Some(tracestate) = &trace_context.tracestate {
+        carrier.insert(TRACESTATE_PROPERTY.to_string(), tracestate.clone());
+    }
+
+    span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}